@@ -9,18 +9,28 @@ use env_logger::{Builder, Env, Target};
 use log::{error, info};
 use std::io::Write;
 use std::process;
-use twin::Twin;
+use twin::{service_config, Twin};
 
 #[tokio::main]
 async fn main() {
-    let mut builder;
     log_panics::init();
 
-    if cfg!(debug_assertions) {
-        builder = Builder::from_env(Env::default().default_filter_or("debug"));
+    // the log level of the service config is needed before we can log anything
+    let service_config = service_config::init();
+
+    let default_level = if cfg!(debug_assertions) {
+        "debug"
     } else {
-        builder = Builder::from_env(Env::default().default_filter_or("info"));
-    }
+        "info"
+    };
+
+    let level = service_config
+        .as_ref()
+        .ok()
+        .and_then(|config| config.logging.level.as_deref())
+        .unwrap_or(default_level);
+
+    let mut builder = Builder::from_env(Env::default().default_filter_or(level));
 
     builder.format(|buf, record| match record.level() {
         log::Level::Info => writeln!(buf, "<6>{}: {}", record.target(), record.args()),
@@ -41,6 +51,12 @@ async fn main() {
     );
     info!("azure sdk version: {}", IotHubClient::sdk_version_string());
 
+    if let Err(e) = service_config {
+        error!("application error: {e:#}");
+
+        process::exit(1);
+    }
+
     if let Err(e) = Twin::run().await {
         error!("application error: {e:#}");
 
@@ -1,12 +1,12 @@
 use anyhow::{bail, ensure, Context, Result};
-use futures_util::{join, StreamExt};
+use futures_util::{join, Stream, StreamExt};
 use log::{debug, info, trace};
 use sd_notify::NotifyState;
 #[cfg(not(feature = "mock"))]
 use std::process::Command;
 use std::{
+    future::Future,
     sync::{Mutex, Once, OnceLock},
-    thread, time,
     time::Duration,
 };
 use systemd_zbus::{ManagerProxy, Mode};
@@ -171,9 +171,7 @@ pub async fn start_unit(timeout_secs: u64, unit: &str) -> Result<()> {
 }
 
 pub async fn wait_for_system_running(timeout_secs: u64) -> Result<()> {
-    let begin = Instant::now();
-    let duration = Duration::from_secs(timeout_secs);
-    let deadline = begin + duration;
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs);
     let system = timeout_at(deadline, zbus::Connection::system()).await??;
     let manager = timeout_at(
         deadline,
@@ -184,34 +182,48 @@ pub async fn wait_for_system_running(timeout_secs: u64) -> Result<()> {
     )
     .await??;
 
-    loop {
-        // timeout_at doesn't return error/timeout if the future returns immediately, so we have to check.
-        // e.g. manager.system_state() returns immediately if using a caching ManagerProxy
-        if begin.elapsed() >= duration {
-            bail!("wait_for_system_running timeout occurred");
-        }
+    /*
+     * Property change streams skip uncached properties, so we wait for the
+     * "StartupFinished" signal instead and re-read the system state on every signal.
+     * We subscribe before reading the current state. Otherwise we could miss the
+     * transition to "running" in between and would wait for a signal that never comes.
+     */
+    let startup_finished =
+        timeout_at(deadline, manager.inner().receive_signal("StartupFinished")).await??;
 
-        let system_state = timeout_at(deadline, manager.system_state()).await??;
+    wait_for_state(deadline, startup_finished, || manager.system_state()).await
+}
+
+async fn wait_for_state<S, F, Fut>(
+    deadline: Instant,
+    mut state_changed: S,
+    mut system_state: F,
+) -> Result<()>
+where
+    S: Stream + Unpin,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = zbus::Result<String>>,
+{
+    loop {
+        let state = timeout_at(deadline, system_state())
+            .await
+            .context("wait_for_system_running timeout occurred")??;
 
-        match system_state.as_str() {
+        match state.as_str() {
             "running" => {
                 debug!("wait_for_system_running: system_state == running");
                 return Ok(());
             }
             "initializing" | "starting" => {
-                /*
-                 * ToDo https://github.com/omnect/omnect-device-service/pull/39#discussion_r1142147564
-                 * This is tricky because you have to receive system state signals, which blocks if you
-                 * are already in state "running". if you receive the system state on condition after
-                 * you got state "starting" by polling, there would by a race which can result in a
-                 * deadlock of receiving the state signal again.
-                 * So a solution would be to poll, start signal receiving, poll again and stop
-                 * possibly stop receiving.
-                 */
-                thread::sleep(time::Duration::from_millis(100));
+                debug!("wait_for_system_running: system_state == {state}");
             }
-            _ => bail!("system in error state: \"{system_state}\""),
+            _ => bail!("system in error state: \"{state}\""),
         }
+
+        timeout_at(deadline, state_changed.next())
+            .await
+            .context("wait_for_system_running timeout occurred")?
+            .context("wait_for_system_running: system state stream closed")?;
     }
 }
 
@@ -219,3 +231,53 @@ pub async fn wait_for_system_running(timeout_secs: u64) -> Result<()> {
 pub async fn reboot() -> Result<()> {
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use std::{cell::RefCell, collections::VecDeque};
+
+    fn states(states: &[&str]) -> RefCell<VecDeque<String>> {
+        RefCell::new(states.iter().map(|state| state.to_string()).collect())
+    }
+
+    #[tokio::test]
+    async fn system_state_changes_to_running() {
+        let states = states(&["initializing", "starting", "running"]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        // every signal triggers a re-read of the state
+        wait_for_state(deadline, stream::iter([(), ()]), || async {
+            Ok(states.borrow_mut().pop_front().unwrap())
+        })
+        .await
+        .unwrap();
+
+        assert!(states.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn system_state_error() {
+        let states = states(&["starting", "degraded"]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let result = wait_for_state(deadline, stream::iter([()]), || async {
+            Ok(states.borrow_mut().pop_front().unwrap())
+        })
+        .await;
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("degraded"));
+    }
+
+    #[tokio::test]
+    async fn system_state_unchanged() {
+        let deadline = Instant::now() + Duration::from_millis(50);
+
+        assert!(wait_for_state(deadline, stream::pending::<()>(), || async {
+            Ok("starting".to_owned())
+        })
+        .await
+        .is_err());
+    }
+}
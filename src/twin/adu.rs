@@ -1,129 +1,112 @@
-use anyhow::{ensure, Context, Result};
+use super::{
+    boot_validation,
+    consent::GeneralConsent,
+    deployment::{
+        self, DeploymentRequest, UpdateManifest, ACTION_CANCEL, ACTION_PROCESS_DEPLOYMENT,
+    },
+    device_info,
+    du_config::{self, AgentConfig, DuConfig},
+    feature::{self, Event, EventStream, Feature},
+    health_check,
+    maintenance_window::{MaintenancePhase, MaintenanceWindows, MaintenanceWindowsConfig},
+    manifest_signature::RootKeys,
+    service_config::{self, DownloadConfig},
+    telemetry::{DeploymentEvent, Telemetry},
+    workflow::{
+        AgentState, ApplyResult, Image, InstallResult, PendingInstall, Phase, Reboot, Workflow,
+        RESULT_FAILURE,
+    },
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use notify::RecommendedWatcher;
 use serde::Serialize;
 use serde_json::json;
-use std::fs::OpenOptions;
-use tokio::sync::mpsc::Sender;
-
-#[macro_export]
-macro_rules! adu_config_path {
-    () => {{
-        if cfg!(feature = "mock") {
-            "testfiles/du-config.json"
-        } else {
-            "/etc/adu/du-config.json"
-        }
-    }};
-}
+use std::{collections::BTreeMap, fs, future::pending, path::PathBuf, sync::Arc};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    select,
+    sync::{mpsc::Sender, watch, Mutex},
+};
 
-#[macro_export]
-macro_rules! sw_versions_path {
-    () => {{
-        if cfg!(feature = "mock") {
-            "testfiles/sw-versions"
-        } else {
-            "/etc/sw-versions"
-        }
-    }};
-}
-
-#[derive(Serialize)]
-struct DeviceInformation {
-    __t: String,
-    manufacturer: String,
-    model: String,
-    osName: String,
-    swVersion: String,
-    processorArchitecture: String,
-    processorManufacturer: String,
-    totalMemory: u32,
-    totalStorage: u32,
-}
+const LAST_WORKFLOW_FILE: &str = "deployment-workflow";
 
-#[derive(Serialize)]
+#[derive(PartialEq, Serialize)]
 struct DeviceProperties {
     manufacturer: String,
     model: String,
-    compatibilityid: String,
+    #[serde(flatten)]
+    additionalDeviceProperties: BTreeMap<String, String>,
     contractModelId: String,
     aduVer: String,
 }
 
-#[derive(Serialize)]
+#[derive(PartialEq, Serialize)]
 struct Agent {
     deviceProperties: DeviceProperties,
     compatPropertyNames: String,
 }
 
-#[derive(Serialize)]
+#[derive(PartialEq, Serialize)]
 struct DeviceUpdate {
     __t: String,
     agent: Agent,
 }
 
-pub struct Adu {
-    tx_reported_properties: Sender<serde_json::Value>,
-    device_info: DeviceInformation,
+// everything we derive from du-config.json
+#[derive(PartialEq)]
+struct AgentProperties {
     device_update: DeviceUpdate,
+    compat_properties: BTreeMap<String, String>,
+    health_check_units: Vec<String>,
 }
 
-impl Adu {
-    pub fn new(tx_reported_properties: Sender<serde_json::Value>) -> Result<Self> {
-        let du_config: serde_json::Value = serde_json::from_reader(
-            OpenOptions::new()
-                .read(true)
-                .create(false)
-                .open(adu_config_path!())
-                .context("cannot read du-config.json")?,
-        )
-        .context("cannot parse du-config.json")?;
-
-        let sw_versions =
-            std::fs::read_to_string(sw_versions_path!()).context("cannot read sw-versions")?;
-
-        let sw_versions: Vec<&str> = sw_versions.split(' ').collect();
-
-        // ToDo: validate by regex?
-        ensure!(
-            sw_versions.len() == 2,
-            "sw-versions: unexpected number of entries"
-        );
+pub struct Adu {
+    tx_reported_properties: Sender<serde_json::Value>,
+    tx_request_reboot: Sender<Reboot>,
+    properties: AgentProperties,
+    deployment_finalized: bool,
+    tx_maintenance_windows: watch::Sender<MaintenanceWindows>,
+    // id of the last canceled workflow
+    tx_cancel: watch::Sender<Option<String>>,
+    // held while a workflow is checked for cancellation and enters its next phase
+    phase_lock: Arc<Mutex<()>>,
+    telemetry: Telemetry,
+    rx_general_consent: watch::Receiver<GeneralConsent>,
+    // including the overrides of the desired service_config
+    rx_download_config: watch::Receiver<DownloadConfig>,
+    last_workflow_id: Option<String>,
+    file_watcher: Option<RecommendedWatcher>,
+}
 
-        // ToDo 1: read cpu arch and manufacturer
-        // ToDo 2: periodically read and report memory consumption
-        let device_info = DeviceInformation {
-            __t: "c".to_owned(),
-            manufacturer: du_config["manufacturer"].as_str().unwrap().to_owned(),
-            model: du_config["model"].as_str().unwrap().to_owned(),
-            osName: sw_versions[0].to_owned(),
-            swVersion: sw_versions[1].to_owned(),
-            processorArchitecture: "aarch64".to_owned(),
-            processorManufacturer: "ARM".to_owned(),
-            totalMemory: 123456,
-            totalStorage: 654321,
-        };
+// the part of Adu which is handed over to spawned deployments
+#[derive(Clone)]
+struct DeploymentContext {
+    tx_reported_properties: Sender<serde_json::Value>,
+    tx_request_reboot: Sender<Reboot>,
+    rx_maintenance_windows: watch::Receiver<MaintenanceWindows>,
+    rx_cancel: watch::Receiver<Option<String>>,
+    phase_lock: Arc<Mutex<()>>,
+    rx_general_consent: watch::Receiver<GeneralConsent>,
+    rx_download_config: watch::Receiver<DownloadConfig>,
+    telemetry: Telemetry,
+    health_check_units: Vec<String>,
+}
 
-        // ToDo 1. iterarte over agents and search for "us"
-        // ToDo 2. adapt agent "name" and "runas"?
+impl AgentProperties {
+    fn new(du_config: &DuConfig, agent_config: &AgentConfig) -> Result<Self> {
         let device_properties = DeviceProperties {
-            manufacturer: du_config["agents"][0]["manufacturer"]
-                .as_str()
-                .unwrap()
-                .to_owned(),
-            model: du_config["agents"][0]["model"].as_str().unwrap().to_owned(),
-            compatibilityid: du_config["agents"][0]["additionalDeviceProperties"]["compatibilityid"]
-                .as_str()
-                .unwrap()
-                .to_owned(),
+            manufacturer: agent_config.manufacturer.clone(),
+            model: agent_config.model.clone(),
+            additionalDeviceProperties: agent_config.additional_device_properties.clone(),
             contractModelId: "dtmi:azure:iot:deviceUpdateContractModel;3".to_owned(),
             aduVer: "DU;agent/1.1.0".to_owned(),
         };
 
         let agent = Agent {
             deviceProperties: device_properties,
-            compatPropertyNames: du_config["compatPropertyNames"]
-                .as_str()
-                .unwrap()
-                .to_owned(),
+            compatPropertyNames: du_config.compat_property_names.clone(),
         };
 
         let device_update = DeviceUpdate {
@@ -131,35 +114,1041 @@ impl Adu {
             agent,
         };
 
+        Ok(AgentProperties {
+            device_update,
+            compat_properties: du_config.compat_properties(agent_config)?,
+            health_check_units: du_config.health_check_units.clone(),
+        })
+    }
+}
+
+impl Adu {
+    pub fn new(
+        du_config: &DuConfig,
+        agent_config: &AgentConfig,
+        tx_reported_properties: Sender<serde_json::Value>,
+        tx_request_reboot: Sender<Reboot>,
+        telemetry: Telemetry,
+        rx_general_consent: watch::Receiver<GeneralConsent>,
+        rx_download_config: watch::Receiver<DownloadConfig>,
+    ) -> Result<Self> {
         Ok(Adu {
             tx_reported_properties,
-            device_info,
-            device_update,
+            tx_request_reboot,
+            properties: AgentProperties::new(du_config, agent_config)?,
+            deployment_finalized: false,
+            tx_maintenance_windows: watch::channel(MaintenanceWindows::from_config(
+                service_config::get().maintenance_windows.clone(),
+            )?)
+            .0,
+            tx_cancel: watch::channel(None).0,
+            phase_lock: Arc::new(Mutex::new(())),
+            telemetry,
+            rx_general_consent,
+            rx_download_config,
+            last_workflow_id: fs::read_to_string(last_workflow_path())
+                .ok()
+                .map(|id| id.trim().to_owned())
+                .filter(|id| !id.is_empty()),
+            file_watcher: None,
         })
     }
 
-    pub async fn report_initial_state(&self) -> Result<()> {
-        self.report_device_info().await?;
-        self.report_device_update().await
+    /*
+     * Re-reads du-config.json. Returns true if anything we report changed.
+     * If the file is invalid we keep the current properties.
+     */
+    fn reload(&mut self) -> Result<bool> {
+        let du_config = DuConfig::load(&service_config::paths().du_config)?;
+        let agent_config = du_config.agent(&du_config::agent_name()?)?;
+        let properties = AgentProperties::new(&du_config, agent_config)?;
+
+        if properties == self.properties {
+            debug!("reload: properties unchanged");
+            return Ok(false);
+        }
+
+        info!("reload: properties changed");
+
+        self.properties = properties;
+
+        Ok(true)
+    }
+
+    /*
+     * An update is compatible if one of the compatibility entries of its manifest consists of
+     * exactly the properties listed in compatPropertyNames and all values match ours.
+     */
+    pub fn is_compatible(&self, compatibility: &[BTreeMap<String, String>]) -> bool {
+        compatibility.iter().any(|entry| {
+            entry.len() == self.properties.compat_properties.len()
+                && entry.iter().all(|(name, value)| {
+                    self.properties
+                        .compat_properties
+                        .get(name)
+                        .is_some_and(|ours| ours.eq_ignore_ascii_case(value))
+                })
+        })
     }
 
-    async fn report_device_info(&self) -> Result<()> {
+    // desired maintenance windows replace the ones of the service config
+    async fn update_maintenance_windows(&self, desired: &serde_json::Value) -> Result<()> {
+        let maintenance_windows = if desired.is_null() {
+            MaintenanceWindows::from_config(service_config::get().maintenance_windows.clone())?
+        } else {
+            MaintenanceWindows::from_desired(desired)?
+        };
+
         self.tx_reported_properties
             .send(json!({
-                "deviceInformation": serde_json::to_value(&self.device_info)?
+                "maintenance_windows": if *maintenance_windows.config() == MaintenanceWindowsConfig::default() {
+                    serde_json::Value::Null
+                } else {
+                    serde_json::to_value(maintenance_windows.config())?
+                }
             }))
             .await
-            .context("report_consent: report_impl")
+            .context("update_maintenance_windows: report_impl")?;
+
+        // waiting workflows get notified and re-evaluate their next window
+        self.tx_maintenance_windows
+            .send_replace(maintenance_windows);
+
+        Ok(())
+    }
+
+    pub fn update_status(&self) -> Result<serde_json::Value> {
+        let paths = service_config::paths();
+        let sw_versions = device_info::sw_versions(&paths.sw_versions, &paths.os_release)?;
+
+        Ok(json!({
+            "workflow": Workflow::load()?,
+            "failed_workflow": Workflow::load_failed()?,
+            "sw_versions": sw_versions.components().collect::<BTreeMap<_, _>>(),
+        }))
+    }
+
+    /*
+     * Only deployments which didn't install anything yet can be canceled. The running
+     * deployment cannot enter its next phase until the cancellation is signaled and the
+     * workflow is removed.
+     */
+    pub async fn cancel_deployment(&self) -> Result<serde_json::Value> {
+        let mut workflow = {
+            let _phase_lock = self.phase_lock.lock().await;
+
+            let workflow =
+                Workflow::load()?.context("cancel_deployment: no deployment in progress")?;
+
+            ensure!(
+                matches!(
+                    workflow.phase,
+                    Phase::Started | Phase::Downloaded | Phase::WaitingForMaintenanceWindow
+                ),
+                "cancel_deployment: workflow {} cannot be canceled in phase {:?}",
+                workflow.id,
+                workflow.phase
+            );
+
+            info!("cancel workflow {}", workflow.id);
+
+            // downloads and handlers waiting for a maintenance window give up
+            self.tx_cancel.send_replace(Some(workflow.id.clone()));
+
+            Workflow::finish()?;
+
+            workflow
+        };
+
+        // a deployment resumed after restart might not have been running
+        remove_downloads();
+
+        workflow
+            .report_result(
+                &self.tx_reported_properties,
+                &InstallResult::failure("deployment canceled"),
+            )
+            .await?;
+
+        self.telemetry
+            .deployment_event(
+                &mut workflow,
+                DeploymentEvent::Failed,
+                Some("deployment canceled"),
+            )
+            .await;
+
+        Ok(json!({ "canceled": workflow.id }))
+    }
+
+    /*
+     * Only deployments which failed after apply, e.g. due to a failed health check, can be
+     * retried since downloaded images are removed after install.
+     */
+    pub async fn retry_last_deployment(&self) -> Result<serde_json::Value> {
+        ensure!(
+            Workflow::load()?.is_none(),
+            "retry_last_deployment: deployment in progress"
+        );
+
+        let mut workflow =
+            Workflow::load_failed()?.context("retry_last_deployment: no failed deployment")?;
+
+        info!("retry workflow {}", workflow.id);
+
+        self.telemetry
+            .deployment_event(&mut workflow, DeploymentEvent::Started, Some("retry"))
+            .await;
+
+        workflow.save()?;
+        Workflow::remove_failed()?;
+
+        workflow
+            .report_state(
+                &self.tx_reported_properties,
+                AgentState::DeploymentInProgress,
+            )
+            .await?;
+
+        let id = workflow.id.clone();
+
+        self.deployment_context().spawn_finalize(workflow, false);
+
+        Ok(json!({ "retried": id }))
+    }
+
+    // checks the given criteria or the ones of the current deployment
+    pub fn check_installed_criteria(
+        &self,
+        payload: &serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let installed_criteria = match payload.get("installed_criteria").and_then(|c| c.as_str()) {
+            Some(installed_criteria) => installed_criteria.to_owned(),
+            None => Workflow::load()?
+                .and_then(|workflow| workflow.installed_criteria)
+                .context("check_installed_criteria: installed_criteria missing")?,
+        };
+
+        let paths = service_config::paths();
+        let sw_versions = device_info::sw_versions(&paths.sw_versions, &paths.os_release)?;
+
+        Ok(json!({
+            "installed_criteria": installed_criteria,
+            "satisfied": sw_versions.satisfies(&installed_criteria),
+            "sw_versions": sw_versions.components().collect::<BTreeMap<_, _>>(),
+        }))
+    }
+
+    /*
+     * Deployments are processed in the background, so that we stay responsive, e.g. for
+     * cancel requests. Every workflow is only processed once.
+     */
+    async fn update_deployment(&mut self, desired: &serde_json::Value) -> Result<()> {
+        let Some(service) = desired.get("service").filter(|service| !service.is_null()) else {
+            return Ok(());
+        };
+
+        let request: DeploymentRequest = serde_json::from_value(service.clone())
+            .context("update_deployment: invalid deployment request")?;
+
+        match request.workflow.action {
+            ACTION_PROCESS_DEPLOYMENT => {}
+            ACTION_CANCEL => {
+                if Workflow::load()?.is_some_and(|workflow| workflow.id == request.workflow.id) {
+                    self.cancel_deployment().await?;
+                }
+
+                return Ok(());
+            }
+            action => bail!("update_deployment: unsupported workflow action {action}"),
+        }
+
+        if self.last_workflow_id.as_deref() == Some(request.workflow.id.as_str()) {
+            debug!("workflow {} already handled", request.workflow.id);
+            return Ok(());
+        }
+
+        ensure!(
+            Workflow::load()?.is_none(),
+            "update_deployment: deployment in progress"
+        );
+
+        // a restart must not repeat the deployment
+        let path = last_workflow_path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("update_deployment: cannot create state dir")?;
+        }
+        fs::write(path, &request.workflow.id)
+            .context("update_deployment: cannot persist workflow id")?;
+        self.last_workflow_id = Some(request.workflow.id.clone());
+
+        let manifest = match self.accept(&request) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                let workflow = Workflow::new(
+                    request.workflow.id,
+                    request.workflow.action,
+                    serde_json::Value::Null,
+                    None,
+                );
+
+                return self.deployment_context().fail(workflow, e).await;
+            }
+        };
+
+        let installed_criteria = manifest
+            .instructions
+            .steps
+            .iter()
+            .find_map(|step| step.handler_properties.installed_criteria.clone());
+
+        let workflow = Workflow::new(
+            request.workflow.id,
+            request.workflow.action,
+            manifest.update_id.clone(),
+            installed_criteria,
+        );
+
+        info!("process workflow {}", workflow.id);
+
+        self.deployment_context()
+            .spawn_deployment(workflow, manifest, request.file_urls);
+
+        Ok(())
+    }
+
+    fn accept(&self, request: &DeploymentRequest) -> Result<UpdateManifest> {
+        let manifest = request.manifest(&RootKeys::load(&service_config::paths().root_keys)?)?;
+
+        ensure!(
+            self.is_compatible(&manifest.compatibility),
+            "update {} is not compatible with this device",
+            manifest.update_id
+        );
+
+        let handlers = &service_config::get().handlers;
+
+        for step in &manifest.instructions.steps {
+            ensure!(
+                handlers.is_enabled(&step.handler),
+                "handler \"{}\" is not enabled",
+                step.handler
+            );
+
+            step.handler_properties.swupdate_arguments()?;
+        }
+
+        Ok(manifest)
+    }
+
+    async fn finalize_deployment(&self) -> Result<()> {
+        let Some(workflow) = Workflow::load()? else {
+            return Ok(());
+        };
+
+        match workflow.phase {
+            Phase::RebootPending => {
+                info!("finalize workflow {} after reboot", workflow.id);
+
+                self.deployment_context().spawn_finalize(workflow, true);
+
+                return Ok(());
+            }
+            Phase::WaitingForRebootWindow => {
+                info!("workflow {} waits for reboot window again", workflow.id);
+
+                self.deployment_context().spawn_reboot(workflow);
+
+                return Ok(());
+            }
+            Phase::WaitingForMaintenanceWindow => {
+                info!("workflow {} waits for install window again", workflow.id);
+
+                self.deployment_context().spawn_install(workflow);
+
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        remove_downloads();
+
+        // swupdate installs into the inactive partition, so the running image is untouched
+        self.deployment_context()
+            .fail(
+                workflow,
+                anyhow!("deployment interrupted by restart of the service"),
+            )
+            .await
+    }
+
+    fn deployment_context(&self) -> DeploymentContext {
+        DeploymentContext {
+            tx_reported_properties: self.tx_reported_properties.clone(),
+            tx_request_reboot: self.tx_request_reboot.clone(),
+            rx_maintenance_windows: self.tx_maintenance_windows.subscribe(),
+            rx_cancel: self.tx_cancel.subscribe(),
+            phase_lock: self.phase_lock.clone(),
+            rx_general_consent: self.rx_general_consent.clone(),
+            rx_download_config: self.rx_download_config.clone(),
+            telemetry: self.telemetry.clone(),
+            health_check_units: self.properties.health_check_units.clone(),
+        }
     }
 
     async fn report_device_update(&self) -> Result<()> {
         self.tx_reported_properties
             .send(json!({
-                "deviceUpdate": serde_json::to_value(&self.device_update)?
+                "deviceUpdate": serde_json::to_value(&self.properties.device_update)?
+            }))
+            .await
+            .context("report_device_update: report_impl")
+    }
+}
+
+impl DeploymentContext {
+    fn spawn_deployment(
+        self,
+        mut workflow: Workflow,
+        manifest: UpdateManifest,
+        file_urls: BTreeMap<String, String>,
+    ) {
+        tokio::spawn(async move {
+            let result = self.deploy(&mut workflow, &manifest, &file_urls).await;
+
+            self.deployment_finished(workflow, result).await;
+        });
+    }
+
+    // resumes a deployment which waited for its install window before restart
+    fn spawn_install(self, mut workflow: Workflow) {
+        tokio::spawn(async move {
+            let result = self.install(&mut workflow).await;
+
+            self.deployment_finished(workflow, result).await;
+        });
+    }
+
+    // the downloaded images are only kept while the deployment is running
+    async fn deployment_finished(&self, workflow: Workflow, result: Result<()>) {
+        remove_downloads();
+
+        let Err(e) = result else {
+            return;
+        };
+
+        // canceled workflows are reported by cancel_deployment
+        let _phase_lock = self.phase_lock.lock().await;
+
+        if self.is_canceled(&workflow) {
+            info!("workflow {} canceled", workflow.id);
+            return;
+        }
+
+        if let Err(e) = self.fail(workflow, e).await {
+            error!("deployment: {e:#}");
+        }
+    }
+
+    async fn deploy(
+        &self,
+        workflow: &mut Workflow,
+        manifest: &UpdateManifest,
+        file_urls: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        self.enter_phase(workflow, Phase::Started).await?;
+
+        workflow
+            .report_state(
+                &self.tx_reported_properties,
+                AgentState::DeploymentInProgress,
+            )
+            .await?;
+
+        // there is no user consent, so consent steps require the general consent
+        for step in &manifest.instructions.steps {
+            if let Some(component) = deployment::consent_component(&step.handler) {
+                ensure!(
+                    self.has_general_consent(component),
+                    "deploy: general consent for {component} missing"
+                );
+            }
+        }
+
+        let download_config = self.rx_download_config.borrow().clone();
+        let download_dir = download_dir();
+        let mut pending_install = PendingInstall {
+            images: vec![],
+            reboot: manifest
+                .instructions
+                .steps
+                .iter()
+                .map(|step| step.handler_properties.reboot)
+                .max()
+                .unwrap_or_default()
+                .reboot(),
+        };
+
+        for step in manifest
+            .instructions
+            .steps
+            .iter()
+            .filter(|step| deployment::is_swupdate(&step.handler))
+        {
+            for file_id in &step.files {
+                let file = manifest
+                    .files
+                    .get(file_id)
+                    .with_context(|| format!("deploy: file {file_id} missing in manifest"))?;
+
+                // swupdate:2 steps also reference the script of the ADU swupdate handler
+                if step
+                    .handler_properties
+                    .swu_file_name
+                    .as_ref()
+                    .is_some_and(|swu_file_name| *swu_file_name != file.file_name)
+                {
+                    debug!("skip {} of workflow {}", file.file_name, workflow.id);
+                    continue;
+                }
+
+                let url = file_urls
+                    .get(file_id)
+                    .with_context(|| format!("deploy: url of file {file_id} missing"))?;
+                let mut rx_cancel = self.rx_cancel.clone();
+
+                let path = select! {
+                    image = deployment::download(file, url, &download_dir, &download_config) => image?,
+                    _ = canceled(&mut rx_cancel, &workflow.id) => bail!("workflow {} canceled", workflow.id),
+                };
+
+                // download verified the size
+                workflow.bytes_transferred += file.size_in_bytes;
+                pending_install.images.push(Image {
+                    path,
+                    swupdate_arguments: step.handler_properties.swupdate_arguments()?,
+                });
+            }
+        }
+
+        workflow.pending_install = Some(pending_install);
+
+        self.enter_phase(workflow, Phase::Downloaded).await?;
+        self.install(workflow).await
+    }
+
+    async fn install(&self, workflow: &mut Workflow) -> Result<()> {
+        self.wait_for_install_window(workflow).await?;
+        self.enter_phase(workflow, Phase::Installing).await?;
+
+        let pending_install = workflow
+            .pending_install
+            .take()
+            .context("install: no images downloaded")?;
+
+        for image in pending_install.images {
+            tokio::task::spawn_blocking(move || {
+                deployment::install(&image.path, &image.swupdate_arguments)
+            })
+            .await
+            .context("install: install task failed")??;
+        }
+
+        self.enter_phase(workflow, Phase::Installed).await?;
+
+        let result = match pending_install.reboot {
+            Some(reboot) => ApplyResult::Reboot(reboot),
+            None => ApplyResult::Success,
+        };
+
+        self.apply_finished(workflow.clone(), result).await
+    }
+
+    // nothing got applied yet, so there is nothing to roll back
+    async fn fail(&self, mut workflow: Workflow, error: anyhow::Error) -> Result<()> {
+        error!("deployment of workflow {} failed: {error:#}", workflow.id);
+
+        let result = InstallResult::failure(format!("{error:#}"));
+
+        Workflow::finish()?;
+
+        workflow
+            .report_result(&self.tx_reported_properties, &result)
+            .await?;
+
+        self.telemetry
+            .deployment_event(
+                &mut workflow,
+                DeploymentEvent::Failed,
+                Some(&result.result_details),
+            )
+            .await;
+
+        Ok(())
+    }
+
+    fn has_general_consent(&self, component: &str) -> bool {
+        self.rx_general_consent.borrow().contains(component)
+    }
+
+    fn is_canceled(&self, workflow: &Workflow) -> bool {
+        self.rx_cancel.borrow().as_deref() == Some(workflow.id.as_str())
+    }
+
+    // a canceled workflow must not be persisted again
+    async fn enter_phase(&self, workflow: &mut Workflow, phase: Phase) -> Result<()> {
+        let _phase_lock = self.phase_lock.lock().await;
+
+        ensure!(
+            !self.is_canceled(workflow),
+            "workflow {} canceled",
+            workflow.id
+        );
+
+        let event = match phase {
+            Phase::Started => Some(DeploymentEvent::Started),
+            Phase::Downloaded => Some(DeploymentEvent::Downloaded),
+            Phase::Installed => Some(DeploymentEvent::Installed),
+            _ => None,
+        };
+
+        workflow.phase = phase;
+
+        if let Some(event) = event {
+            self.telemetry.deployment_event(workflow, event, None).await;
+        }
+
+        workflow.save()
+    }
+
+    async fn wait_for_install_window(&self, workflow: &mut Workflow) -> Result<()> {
+        if !self
+            .rx_maintenance_windows
+            .borrow()
+            .applies_to(MaintenancePhase::Install)
+        {
+            return Ok(());
+        }
+
+        self.enter_phase(workflow, Phase::WaitingForMaintenanceWindow)
+            .await?;
+
+        wait_for_maintenance_window(
+            workflow,
+            MaintenancePhase::Install,
+            self.rx_maintenance_windows.clone(),
+            self.rx_cancel.clone(),
+            &self.tx_reported_properties,
+        )
+        .await
+    }
+
+    async fn apply_finished(&self, mut workflow: Workflow, result: ApplyResult) -> Result<()> {
+        info!("apply of workflow {} finished: {result:?}", workflow.id);
+
+        workflow.phase = Phase::Applied;
+
+        self.telemetry
+            .deployment_event(&mut workflow, DeploymentEvent::Applied, None)
+            .await;
+
+        match result {
+            ApplyResult::Success => {
+                self.spawn_finalize(workflow, false);
+                Ok(())
+            }
+            ApplyResult::Reboot(Reboot::Immediate) => {
+                self.reboot(workflow, Reboot::Immediate).await
+            }
+            ApplyResult::Reboot(Reboot::Required) => {
+                // persist first, so that we resume waiting for the reboot window after restart
+                workflow.phase = Phase::WaitingForRebootWindow;
+                workflow.save()?;
+
+                workflow
+                    .report_state(
+                        &self.tx_reported_properties,
+                        AgentState::DeploymentInProgress,
+                    )
+                    .await?;
+
+                self.spawn_reboot(workflow);
+
+                Ok(())
+            }
+        }
+    }
+
+    // a required reboot is deferred until the next maintenance window
+    fn spawn_reboot(&self, workflow: Workflow) {
+        let context = self.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = wait_for_maintenance_window(
+                &workflow,
+                MaintenancePhase::Reboot,
+                context.rx_maintenance_windows.clone(),
+                context.rx_cancel.clone(),
+                &context.tx_reported_properties,
+            )
+            .await
+            {
+                error!("wait for reboot window: {e:#}");
+                return;
+            }
+
+            if let Err(e) = context.reboot(workflow, Reboot::Required).await {
+                error!("request reboot: {e:#}");
+            }
+        });
+    }
+
+    async fn reboot(&self, mut workflow: Workflow, reboot: Reboot) -> Result<()> {
+        // persist first, so that we are able to finalize the deployment after reboot
+        workflow.phase = Phase::RebootPending;
+        workflow.save()?;
+
+        workflow
+            .report_state(
+                &self.tx_reported_properties,
+                AgentState::DeploymentInProgress,
+            )
+            .await?;
+
+        self.tx_request_reboot
+            .send(reboot)
+            .await
+            .context("reboot: request reboot")
+    }
+
+    fn spawn_finalize(&self, workflow: Workflow, validate_boot: bool) {
+        let tx_reported_properties = self.tx_reported_properties.clone();
+        let tx_request_reboot = self.tx_request_reboot.clone();
+        let health_check_units = self.health_check_units.clone();
+        let telemetry = self.telemetry.clone();
+
+        // validation and health checks wait for systemd jobs, so we must not block the event loop
+        tokio::spawn(async move {
+            if let Err(e) = finalize(
+                workflow,
+                validate_boot,
+                &health_check_units,
+                &tx_reported_properties,
+                &tx_request_reboot,
+                &telemetry,
+            )
+            .await
+            {
+                error!("finalize deployment: {e:#}");
+            }
+        });
+    }
+}
+
+#[async_trait(?Send)]
+impl Feature for Adu {
+    fn name(&self) -> &'static str {
+        "device_update"
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn desired_properties(&self) -> &'static [&'static str] {
+        &["deviceUpdate", "maintenance_windows"]
+    }
+
+    fn direct_methods(&self) -> &'static [&'static str] {
+        &[
+            "get_update_status",
+            "cancel_deployment",
+            "retry_last_deployment",
+            "check_installed_criteria",
+        ]
+    }
+
+    async fn handle_desired(&mut self, property: &str, desired: &serde_json::Value) -> Result<()> {
+        match property {
+            "deviceUpdate" => self.update_deployment(desired).await,
+            "maintenance_windows" => self.update_maintenance_windows(desired).await,
+            _ => bail!("unknown desired property \"{property}\""),
+        }
+    }
+
+    async fn handle_direct_method(
+        &mut self,
+        method: &str,
+        payload: &serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        match method {
+            "get_update_status" => self.update_status(),
+            "cancel_deployment" => self.cancel_deployment().await,
+            "retry_last_deployment" => self.retry_last_deployment().await,
+            "check_installed_criteria" => self.check_installed_criteria(payload),
+            _ => Err(anyhow!("unknown direct method \"{method}\"")),
+        }
+    }
+
+    async fn report_initial_state(&mut self) -> Result<()> {
+        self.report_device_update().await?;
+
+        // we only have to finalize once after start, not on every reconnect
+        if !self.deployment_finalized {
+            self.deployment_finalized = true;
+            self.finalize_deployment().await?;
+        }
+
+        Ok(())
+    }
+
+    fn event_stream(&mut self) -> Result<Option<EventStream>> {
+        let (file_watcher, file_changed) =
+            feature::file_stream(&[service_config::paths().du_config.as_path()])?;
+
+        self.file_watcher = Some(file_watcher);
+
+        Ok(Some(file_changed))
+    }
+
+    async fn handle_event(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::FileChanged => match self.reload() {
+                Ok(true) => self.report_device_update().await,
+                Ok(false) => Ok(()),
+                Err(e) => {
+                    warn!("keep current properties, since reload failed: {e:#}");
+                    Ok(())
+                }
+            },
+            Event::Interval => Ok(()),
+        }
+    }
+}
+
+// resolves once the workflow got canceled
+async fn canceled(rx_cancel: &mut watch::Receiver<Option<String>>, id: &str) {
+    while rx_cancel.borrow_and_update().as_deref() != Some(id) {
+        if rx_cancel.changed().await.is_err() {
+            pending::<()>().await;
+        }
+    }
+}
+
+async fn wait_for_maintenance_window(
+    workflow: &Workflow,
+    phase: MaintenancePhase,
+    mut rx_maintenance_windows: watch::Receiver<MaintenanceWindows>,
+    mut rx_cancel: watch::Receiver<Option<String>>,
+    tx_reported_properties: &Sender<serde_json::Value>,
+) -> Result<()> {
+    let mut waiting = false;
+
+    loop {
+        if rx_cancel.borrow_and_update().as_deref() == Some(workflow.id.as_str()) {
+            if waiting {
+                tx_reported_properties
+                    .send(json!({ "maintenance_window_wait": null }))
+                    .await
+                    .context("wait_for_maintenance_window: report_impl")?;
+            }
+
+            bail!("workflow {} canceled", workflow.id);
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let next_window_start = {
+            let maintenance_windows = rx_maintenance_windows.borrow_and_update();
+
+            if maintenance_windows.applies_to(phase) {
+                maintenance_windows.next_window_start(now)
+            } else {
+                None
+            }
+        };
+
+        let Some(next_window_start) = next_window_start.filter(|start| now < *start) else {
+            break;
+        };
+
+        info!(
+            "workflow {} waits for {phase:?} window starting at {next_window_start}",
+            workflow.id
+        );
+
+        tx_reported_properties
+            .send(json!({
+                "maintenance_window_wait": {
+                    "workflow_id": workflow.id,
+                    "phase": phase,
+                    "next_window_start": next_window_start.format(&Rfc3339)?
+                }
             }))
             .await
-            .context("report_consent: report_impl")
+            .context("wait_for_maintenance_window: report_impl")?;
+
+        waiting = true;
+
+        select! {
+            _ = tokio::time::sleep((next_window_start - now).try_into()?) => {},
+            changed = rx_maintenance_windows.changed() => {
+                changed.context("wait_for_maintenance_window: maintenance windows closed")?
+            },
+            changed = rx_cancel.changed() => {
+                changed.context("wait_for_maintenance_window: cancel closed")?
+            },
+        }
+    }
+
+    if waiting {
+        tx_reported_properties
+            .send(json!({ "maintenance_window_wait": null }))
+            .await
+            .context("wait_for_maintenance_window: report_impl")?;
+    }
+
+    Ok(())
+}
+
+async fn finalize(
+    mut workflow: Workflow,
+    validate_boot: bool,
+    health_check_units: &[String],
+    tx_reported_properties: &Sender<serde_json::Value>,
+    tx_request_reboot: &Sender<Reboot>,
+    telemetry: &Telemetry,
+) -> Result<()> {
+    if validate_boot {
+        telemetry
+            .deployment_event(&mut workflow, DeploymentEvent::Rebooted, None)
+            .await;
+
+        if let Err(e) = boot_validation::validate(&workflow).await {
+            return rollback(
+                workflow,
+                InstallResult::failure(format!("boot validation failed: {e:#}")),
+                tx_reported_properties,
+                tx_request_reboot,
+                telemetry,
+            )
+            .await;
+        }
+    }
+
+    let result = match health_check::run(health_check_units).await {
+        None => InstallResult::success(),
+        Some(health_check) if health_check.is_success() => {
+            InstallResult::success().with_step(health_check::STEP_NAME, health_check)
+        }
+        Some(health_check) => InstallResult::failure("health check failed")
+            .with_step(health_check::STEP_NAME, health_check),
+    };
+
+    if result.result_code != RESULT_FAILURE {
+        workflow
+            .report_result(tx_reported_properties, &result)
+            .await?;
+
+        telemetry
+            .deployment_event(&mut workflow, DeploymentEvent::Succeeded, None)
+            .await;
+
+        return Workflow::finish();
+    }
+
+    // without a reboot we are still running the previous image, so there is nothing to roll back
+    if !validate_boot {
+        workflow
+            .report_result(tx_reported_properties, &result)
+            .await?;
+
+        telemetry
+            .deployment_event(
+                &mut workflow,
+                DeploymentEvent::Failed,
+                Some(&result.result_details),
+            )
+            .await;
+
+        workflow.phase = Phase::Applied;
+        workflow.save_failed()?;
+
+        return Workflow::finish();
     }
+
+    rollback(
+        workflow,
+        result,
+        tx_reported_properties,
+        tx_request_reboot,
+        telemetry,
+    )
+    .await
+}
+
+async fn rollback(
+    mut workflow: Workflow,
+    mut result: InstallResult,
+    tx_reported_properties: &Sender<serde_json::Value>,
+    tx_request_reboot: &Sender<Reboot>,
+    telemetry: &Telemetry,
+) -> Result<()> {
+    error!(
+        "deployment of workflow {} failed: {}",
+        workflow.id, result.result_details
+    );
+
+    // we only remove the workflow if rollback succeeded, otherwise we would retry on next start
+    if let Err(e) = boot_validation::rollback() {
+        result.result_details = format!("{}, rollback failed: {e:#}", result.result_details);
+        workflow
+            .report_result(tx_reported_properties, &result)
+            .await?;
+        telemetry
+            .deployment_event(
+                &mut workflow,
+                DeploymentEvent::Failed,
+                Some(&result.result_details),
+            )
+            .await;
+        return Err(e);
+    }
+
+    workflow
+        .report_result(tx_reported_properties, &result)
+        .await?;
+    telemetry
+        .deployment_event(
+            &mut workflow,
+            DeploymentEvent::Failed,
+            Some(&result.result_details),
+        )
+        .await;
+    Workflow::finish()?;
+
+    // boot into the previous image
+    tx_request_reboot
+        .send(Reboot::Immediate)
+        .await
+        .context("rollback: request reboot")
+}
+
+fn download_dir() -> PathBuf {
+    service_config::paths().download_dir.join("deployment")
+}
+
+fn remove_downloads() {
+    let download_dir = download_dir();
+
+    if download_dir.exists() {
+        if let Err(e) = fs::remove_dir_all(&download_dir) {
+            warn!("cannot remove {}: {e}", download_dir.display());
+        }
+    }
+}
+
+fn last_workflow_path() -> PathBuf {
+    service_config::paths().state_dir.join(LAST_WORKFLOW_FILE)
 }
 /*
 "DeviceInformation" {
@@ -222,3 +1211,307 @@ impl Adu {
         "av": 3
     }
 }, */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twin::workflow::RESULT_APPLY_SUCCESS;
+    use azure_iot_sdk::client::IotMessage;
+    use std::sync::OnceLock;
+    use tokio::sync::{mpsc, MutexGuard};
+
+    // all tests share the state directory of the test config
+    static STATE_DIR_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+
+    struct TestAdu {
+        adu: Adu,
+        rx_reported_properties: mpsc::Receiver<serde_json::Value>,
+        rx_request_reboot: mpsc::Receiver<Reboot>,
+        _rx_d2c_messages: mpsc::Receiver<IotMessage>,
+        _state_dir_lock: MutexGuard<'static, ()>,
+    }
+
+    impl TestAdu {
+        async fn new() -> Self {
+            let state_dir_lock = STATE_DIR_LOCK.get_or_init(Default::default).lock().await;
+            let paths = service_config::paths();
+
+            _ = fs::remove_dir_all(&paths.state_dir);
+            fs::create_dir_all(&paths.state_dir).unwrap();
+
+            let du_config = DuConfig::load(&paths.du_config).unwrap();
+            let (tx_reported_properties, rx_reported_properties) = mpsc::channel(100);
+            let (tx_request_reboot, rx_request_reboot) = mpsc::channel(1);
+            let (tx_d2c_messages, rx_d2c_messages) = mpsc::channel(100);
+
+            let adu = Adu::new(
+                &du_config,
+                du_config.agent("AducIotAgent").unwrap(),
+                tx_reported_properties,
+                tx_request_reboot,
+                Telemetry::new(tx_d2c_messages),
+                watch::channel(GeneralConsent::default()).1,
+                watch::channel(service_config::get().download.clone()).1,
+            )
+            .unwrap();
+
+            TestAdu {
+                adu,
+                rx_reported_properties,
+                rx_request_reboot,
+                _rx_d2c_messages: rx_d2c_messages,
+                _state_dir_lock: state_dir_lock,
+            }
+        }
+
+        // waits for the reported lastInstallResult
+        async fn install_result(&mut self) -> serde_json::Value {
+            self.reported("/deviceUpdate/agent/lastInstallResult").await
+        }
+
+        async fn reported(&mut self, pointer: &str) -> serde_json::Value {
+            loop {
+                let reported = self.rx_reported_properties.recv().await.unwrap();
+
+                if let Some(value) = reported.pointer(pointer) {
+                    return value.clone();
+                }
+            }
+        }
+    }
+
+    fn workflow(phase: Phase) -> Workflow {
+        let mut workflow = Workflow::new(
+            "wf".to_owned(),
+            ACTION_PROCESS_DEPLOYMENT,
+            json!({"provider": "omnect", "name": "image", "version": "1.0"}),
+            Some("OMNECT-gateway-devel 4.0.17.123456".to_owned()),
+        );
+
+        workflow.phase = phase;
+        workflow
+    }
+
+    #[tokio::test]
+    async fn cancel_before_install_only() {
+        let mut test = TestAdu::new().await;
+
+        assert!(test.adu.cancel_deployment().await.is_err());
+
+        for phase in [
+            Phase::Started,
+            Phase::Downloaded,
+            Phase::WaitingForMaintenanceWindow,
+        ] {
+            test.adu.tx_cancel.send_replace(None);
+            workflow(phase).save().unwrap();
+
+            assert_eq!(
+                test.adu.cancel_deployment().await.unwrap(),
+                json!({"canceled": "wf"})
+            );
+            assert!(Workflow::load().unwrap().is_none());
+            assert_eq!(test.adu.tx_cancel.borrow().as_deref(), Some("wf"));
+            assert_eq!(
+                test.install_result().await["resultDetails"],
+                "deployment canceled"
+            );
+        }
+
+        for phase in [
+            Phase::Installing,
+            Phase::Installed,
+            Phase::Applied,
+            Phase::WaitingForRebootWindow,
+            Phase::RebootPending,
+        ] {
+            test.adu.tx_cancel.send_replace(None);
+            workflow(phase.clone()).save().unwrap();
+
+            assert!(test.adu.cancel_deployment().await.is_err());
+            assert_eq!(Workflow::load().unwrap().unwrap().phase, phase);
+            assert!(test.adu.tx_cancel.borrow().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn canceled_workflow_does_not_enter_next_phase() {
+        let test = TestAdu::new().await;
+        let context = test.adu.deployment_context();
+        let mut workflow = workflow(Phase::Downloaded);
+
+        workflow.save().unwrap();
+        test.adu.cancel_deployment().await.unwrap();
+
+        assert!(context
+            .enter_phase(&mut workflow, Phase::Installing)
+            .await
+            .is_err());
+        assert!(Workflow::load().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn retry_failed_workflow() {
+        let mut test = TestAdu::new().await;
+
+        assert!(test.adu.retry_last_deployment().await.is_err());
+
+        workflow(Phase::Applied).save_failed().unwrap();
+        workflow(Phase::Started).save().unwrap();
+
+        assert!(test.adu.retry_last_deployment().await.is_err());
+
+        Workflow::remove().unwrap();
+
+        assert_eq!(
+            test.adu.retry_last_deployment().await.unwrap(),
+            json!({"retried": "wf"})
+        );
+        assert!(Workflow::load_failed().unwrap().is_none());
+
+        // without health check units the retried deployment succeeds right away
+        assert_eq!(
+            test.install_result().await["resultCode"],
+            RESULT_APPLY_SUCCESS
+        );
+        assert!(Workflow::load().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn installed_criteria_against_sw_versions() {
+        let test = TestAdu::new().await;
+
+        let checked = test
+            .adu
+            .check_installed_criteria(
+                &json!({"installed_criteria": "OMNECT-gateway-devel 4.0.17.123456"}),
+            )
+            .unwrap();
+        assert_eq!(checked["satisfied"], true);
+        assert_eq!(
+            checked["sw_versions"],
+            json!({"OMNECT-gateway-devel": "4.0.17.123456"})
+        );
+
+        let checked = test
+            .adu
+            .check_installed_criteria(&json!({"installed_criteria": "OMNECT-gateway-devel 5.0"}))
+            .unwrap();
+        assert_eq!(checked["satisfied"], false);
+
+        // the criteria of the current deployment are used if none are given
+        assert!(test
+            .adu
+            .check_installed_criteria(&serde_json::Value::Null)
+            .is_err());
+
+        workflow(Phase::Installed).save().unwrap();
+
+        let checked = test
+            .adu
+            .check_installed_criteria(&serde_json::Value::Null)
+            .unwrap();
+        assert_eq!(
+            checked["installed_criteria"],
+            "OMNECT-gateway-devel 4.0.17.123456"
+        );
+        assert_eq!(checked["satisfied"], true);
+    }
+
+    #[tokio::test]
+    async fn finalize_after_restart() {
+        let mut test = TestAdu::new().await;
+
+        // nothing to do
+        test.adu.finalize_deployment().await.unwrap();
+
+        // an interrupted download is not resumed
+        workflow(Phase::Downloaded).save().unwrap();
+        test.adu.finalize_deployment().await.unwrap();
+
+        assert!(test.install_result().await["resultDetails"]
+            .as_str()
+            .unwrap()
+            .contains("interrupted by restart"));
+        assert!(Workflow::load().unwrap().is_none());
+
+        // without maintenance windows the pending reboot is requested right away
+        workflow(Phase::WaitingForRebootWindow).save().unwrap();
+        test.adu.finalize_deployment().await.unwrap();
+
+        assert_eq!(test.rx_request_reboot.recv().await, Some(Reboot::Required));
+        assert_eq!(
+            Workflow::load().unwrap().unwrap().phase,
+            Phase::RebootPending
+        );
+    }
+
+    #[tokio::test]
+    async fn rollback_after_failed_boot_validation() {
+        let mut test = TestAdu::new().await;
+        let mut workflow = workflow(Phase::RebootPending);
+
+        // we booted into the previous image
+        workflow.installed_criteria = Some("OMNECT-gateway-devel 5.0".to_owned());
+        workflow.save().unwrap();
+
+        test.adu.finalize_deployment().await.unwrap();
+
+        let result = test.install_result().await;
+        assert_eq!(result["resultCode"], RESULT_FAILURE);
+        assert!(result["resultDetails"]
+            .as_str()
+            .unwrap()
+            .starts_with("boot validation failed"));
+        assert_eq!(test.rx_request_reboot.recv().await, Some(Reboot::Immediate));
+        assert!(Workflow::load().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn resume_waiting_for_install_window() {
+        let mut test = TestAdu::new().await;
+        let image = download_dir().join("image.swu");
+
+        fs::create_dir_all(download_dir()).unwrap();
+        fs::write(&image, "image").unwrap();
+
+        let mut workflow = workflow(Phase::WaitingForMaintenanceWindow);
+        workflow.pending_install = Some(PendingInstall {
+            images: vec![Image {
+                path: image.clone(),
+                swupdate_arguments: vec![],
+            }],
+            reboot: Some(Reboot::Required),
+        });
+        workflow.save().unwrap();
+
+        // the only window opens in two hours
+        let hour = OffsetDateTime::now_utc().hour();
+        test.adu.tx_maintenance_windows.send_replace(
+            MaintenanceWindows::from_desired(&json!({
+                "phases": ["install"],
+                "windows": [{
+                    "start": format!("{}:00", (hour + 2) % 24),
+                    "end": format!("{}:00", (hour + 3) % 24)
+                }]
+            }))
+            .unwrap(),
+        );
+
+        test.adu.finalize_deployment().await.unwrap();
+
+        let wait = test.reported("/maintenance_window_wait").await;
+        assert_eq!(wait["workflow_id"], "wf");
+        assert_eq!(wait["phase"], "install");
+
+        let waiting = Workflow::load().unwrap().unwrap();
+        assert_eq!(waiting.phase, Phase::WaitingForMaintenanceWindow);
+        assert_eq!(waiting.pending_install, workflow.pending_install);
+        assert!(image.exists());
+
+        // downloaded images are removed once the deployment ended
+        test.adu.cancel_deployment().await.unwrap();
+
+        assert!(!download_dir().exists());
+    }
+}
@@ -0,0 +1,79 @@
+use super::{device_info, service_config, workflow::Workflow};
+use crate::systemd;
+use anyhow::{ensure, Context, Result};
+use log::info;
+use std::{path::Path, process::Command};
+
+const SYSTEM_RUNNING_TIMEOUT_SECS: u64 = 300;
+
+pub async fn validate(workflow: &Workflow) -> Result<()> {
+    info!("validate boot of workflow {}", workflow.id);
+
+    if let Some(installed_criteria) = &workflow.installed_criteria {
+        let paths = service_config::paths();
+        let sw_versions = device_info::sw_versions(&paths.sw_versions, &paths.os_release)?;
+
+        ensure!(
+            sw_versions.satisfies(installed_criteria),
+            "installed criteria \"{installed_criteria}\" not satisfied by sw-versions {:?}",
+            sw_versions.components().collect::<Vec<_>>()
+        );
+    }
+
+    systemd::wait_for_system_running(SYSTEM_RUNNING_TIMEOUT_SECS)
+        .await
+        .context("system didn't reach running state")
+}
+
+pub fn rollback() -> Result<()> {
+    info!("trigger rollback");
+
+    run_hook(&service_config::paths().rollback_hook)
+}
+
+fn run_hook(hook: &Path) -> Result<()> {
+    let status = Command::new(hook)
+        .status()
+        .context("rollback: failed to execute rollback hook")?;
+
+    ensure!(status.success(), "rollback: hook failed with {status}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twin::deployment::ACTION_PROCESS_DEPLOYMENT;
+
+    #[test]
+    fn rollback_hook() {
+        rollback().unwrap();
+
+        assert!(run_hook(Path::new("testfiles/not-existing-hook"))
+            .unwrap_err()
+            .to_string()
+            .contains("failed to execute"));
+        assert!(run_hook(Path::new("false"))
+            .unwrap_err()
+            .to_string()
+            .contains("hook failed"));
+    }
+
+    #[tokio::test]
+    async fn installed_criteria_not_satisfied() {
+        let workflow = Workflow::new(
+            "wf".to_owned(),
+            ACTION_PROCESS_DEPLOYMENT,
+            serde_json::Value::Null,
+            Some("OMNECT-gateway-devel 5.0".to_owned()),
+        );
+
+        // fails before waiting for systemd
+        assert!(validate(&workflow)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("not satisfied"));
+    }
+}
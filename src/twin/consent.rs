@@ -0,0 +1,173 @@
+use super::{feature::Feature, service_config};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{fs, path::PathBuf};
+use tokio::sync::{mpsc::Sender, watch};
+
+pub const CONSENT_FILE: &str = "consent.json";
+
+/*
+ * Components listed in the general consent, e.g. ["swupdate"], are updated without
+ * asking the user. Consent steps of all other components fail the deployment.
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct GeneralConsent {
+    general_consent: Vec<String>,
+}
+
+impl GeneralConsent {
+    // no consent is given as long as nothing is stored
+    pub fn load() -> Result<Self> {
+        let path = consent_path();
+
+        if !path.exists() {
+            return Ok(GeneralConsent::default());
+        }
+
+        serde_json::from_str(&fs::read_to_string(&path).context("load: cannot read consent")?)
+            .with_context(|| format!("load: invalid {}", path.display()))
+    }
+
+    /*
+     * Entries are case insensitive, e.g. ["SWUpdate", "swupdate"] becomes ["swupdate"].
+     * null revokes the consent, e.g. if the desired property got removed.
+     */
+    pub fn from_desired(desired: &Value) -> Result<Self> {
+        let desired = match desired {
+            Value::Null => return Ok(GeneralConsent::default()),
+            Value::Array(desired) => desired,
+            _ => bail!("general consent: array expected"),
+        };
+
+        let mut general_consent = desired
+            .iter()
+            .map(|component| {
+                component
+                    .as_str()
+                    .map(|component| component.to_lowercase())
+                    .with_context(|| format!("general consent: invalid component {component}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        general_consent.sort();
+        general_consent.dedup();
+
+        Ok(GeneralConsent { general_consent })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = consent_path();
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("save: cannot create state dir")?;
+        }
+
+        let tmp_path = path.with_extension("tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?).context("save: cannot write")?;
+        fs::rename(&tmp_path, &path).context("save: cannot rename")
+    }
+
+    pub fn contains(&self, component: &str) -> bool {
+        self.general_consent
+            .iter()
+            .any(|consent| consent.eq_ignore_ascii_case(component))
+    }
+
+    pub fn components(&self) -> &[String] {
+        &self.general_consent
+    }
+}
+
+// deployments read the general consent via a watch receiver
+pub struct Consent {
+    tx_reported_properties: Sender<Value>,
+    tx_general_consent: watch::Sender<GeneralConsent>,
+}
+
+impl Consent {
+    pub fn new(
+        tx_reported_properties: Sender<Value>,
+        tx_general_consent: watch::Sender<GeneralConsent>,
+    ) -> Self {
+        Consent {
+            tx_reported_properties,
+            tx_general_consent,
+        }
+    }
+
+    async fn update_general_consent(&self, desired: &Value) -> Result<()> {
+        let general_consent = GeneralConsent::from_desired(desired)?;
+
+        if general_consent != *self.tx_general_consent.borrow() {
+            general_consent.save()?;
+            self.tx_general_consent.send_replace(general_consent);
+        }
+
+        let reported = json!({
+            "device_update_consent": {
+                "general_consent": self.tx_general_consent.borrow().components()
+            }
+        });
+
+        self.tx_reported_properties
+            .send(reported)
+            .await
+            .context("update_general_consent: report_impl")
+    }
+}
+
+#[async_trait(?Send)]
+impl Feature for Consent {
+    fn name(&self) -> &'static str {
+        "device_update_consent"
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn desired_properties(&self) -> &'static [&'static str] {
+        &["general_consent"]
+    }
+
+    async fn handle_desired(&mut self, property: &str, desired: &Value) -> Result<()> {
+        match property {
+            "general_consent" => self.update_general_consent(desired).await,
+            _ => bail!("unknown desired property \"{property}\""),
+        }
+    }
+}
+
+fn consent_path() -> PathBuf {
+    service_config::paths().state_dir.join(CONSENT_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn general_consent_from_desired() {
+        let consent =
+            GeneralConsent::from_desired(&json!(["SWUpdate", "bootloader", "swupdate"])).unwrap();
+
+        assert_eq!(consent.components(), ["bootloader", "swupdate"]);
+        assert!(consent.contains("SwUpdate"));
+        assert!(!consent.contains("firmware"));
+
+        assert!(GeneralConsent::from_desired(&json!(["swupdate", 1])).is_err());
+        assert!(GeneralConsent::from_desired(&json!("swupdate")).is_err());
+        assert!(GeneralConsent::from_desired(&json!([]))
+            .unwrap()
+            .components()
+            .is_empty());
+        assert_eq!(
+            GeneralConsent::from_desired(&serde_json::Value::Null).unwrap(),
+            GeneralConsent::default()
+        );
+    }
+}
@@ -0,0 +1,541 @@
+use super::{
+    manifest_signature::{self, RootKeys},
+    service_config::DownloadConfig,
+    workflow::Reboot,
+};
+use anyhow::{bail, ensure, Context, Result};
+use log::info;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+// workflow actions as defined by the device update pnp interface
+pub const ACTION_PROCESS_DEPLOYMENT: u8 = 3;
+pub const ACTION_CANCEL: u8 = 255;
+
+// update types installed by swupdate
+const SWUPDATE_HANDLERS: [&str; 2] = ["microsoft/swupdate:1", "microsoft/swupdate:2"];
+// update types which only check the consent of the component they are named after
+const CONSENT_HANDLERS: [(&str, &str); 1] = [("omnect/swupdate_consent:1", "swupdate")];
+
+/*
+ * desired "deviceUpdate" component:
+ * {
+ *     "__t": "c",
+ *     "service": {
+ *         "workflow": { "action": 3, "id": "..." },
+ *         "updateManifest": "<json string, see UpdateManifest>",
+ *         "updateManifestSignature": "<JWS, see manifest_signature>",
+ *         "fileUrls": { "<file id>": "http://..." }
+ *     }
+ * }
+ */
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentRequest {
+    pub workflow: WorkflowRequest,
+    #[serde(default)]
+    update_manifest: Option<String>,
+    #[serde(default)]
+    update_manifest_signature: Option<String>,
+    #[serde(default)]
+    pub file_urls: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkflowRequest {
+    pub action: u8,
+    pub id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateManifest {
+    pub update_id: serde_json::Value,
+    #[serde(default)]
+    pub compatibility: Vec<BTreeMap<String, String>>,
+    pub instructions: Instructions,
+    #[serde(default)]
+    pub files: BTreeMap<String, FileEntity>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Instructions {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Step {
+    pub handler: String,
+    #[serde(default)]
+    pub files: Vec<String>,
+    #[serde(default)]
+    pub handler_properties: HandlerProperties,
+}
+
+/*
+ * "handlerProperties": {
+ *     "installedCriteria": "<component> <version>",
+ *     "swuFileName": "the image to install, all files of the step are installed if missing",
+ *     "arguments": "-e <software>,<mode>", e.g. "-e stable,copy2", nothing else is passed on",
+ *     "reboot": "none", "required" (default) or "immediate"
+ * }
+ * A required reboot waits for the reboot maintenance window, an immediate one doesn't.
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HandlerProperties {
+    pub installed_criteria: Option<String>,
+    pub swu_file_name: Option<String>,
+    pub arguments: Option<String>,
+    #[serde(default)]
+    pub reboot: RebootMode,
+}
+
+// ordered, so that the strongest demand of all steps wins
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum RebootMode {
+    None,
+    #[default]
+    Required,
+    Immediate,
+}
+
+impl RebootMode {
+    pub fn reboot(self) -> Option<Reboot> {
+        match self {
+            RebootMode::None => None,
+            RebootMode::Required => Some(Reboot::Required),
+            RebootMode::Immediate => Some(Reboot::Immediate),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileEntity {
+    pub file_name: String,
+    pub size_in_bytes: u64,
+    // base64 encoded, e.g. { "sha256": "..." }
+    #[serde(default)]
+    pub hashes: BTreeMap<String, String>,
+}
+
+impl DeploymentRequest {
+    // the hashes of the files are only worth something if the manifest is authentic
+    pub fn manifest(&self, root_keys: &RootKeys) -> Result<UpdateManifest> {
+        let manifest = self
+            .update_manifest
+            .as_deref()
+            .context("manifest: updateManifest missing")?;
+        let signature = self
+            .update_manifest_signature
+            .as_deref()
+            .context("manifest: updateManifestSignature missing")?;
+
+        manifest_signature::verify(manifest, signature, root_keys)?;
+
+        parse_manifest(manifest)
+    }
+}
+
+impl HandlerProperties {
+    // swupdate runs as root, so the cloud may only select the software collection
+    pub fn swupdate_arguments(&self) -> Result<Vec<String>> {
+        let Some(arguments) = self.arguments.as_deref() else {
+            return Ok(vec![]);
+        };
+
+        match arguments.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => Ok(vec![]),
+            ["-e", selection]
+                if !selection.starts_with('-')
+                    && selection.chars().all(|c| {
+                        c.is_ascii_alphanumeric() || matches!(c, ',' | '_' | '-' | '.')
+                    }) =>
+            {
+                Ok(vec!["-e".to_owned(), selection.to_owned()])
+            }
+            _ => bail!("unsupported swupdate arguments \"{arguments}\", only \"-e <software>,<mode>\" is allowed"),
+        }
+    }
+}
+
+fn parse_manifest(manifest: &str) -> Result<UpdateManifest> {
+    let manifest: UpdateManifest =
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(manifest))
+            .context("manifest: invalid updateManifest")?;
+
+    ensure!(
+        !manifest.instructions.steps.is_empty(),
+        "manifest: no steps"
+    );
+
+    Ok(manifest)
+}
+
+pub fn is_swupdate(handler: &str) -> bool {
+    SWUPDATE_HANDLERS.contains(&handler)
+}
+
+pub fn consent_component(handler: &str) -> Option<&'static str> {
+    CONSENT_HANDLERS
+        .iter()
+        .find_map(|(consent_handler, component)| {
+            (*consent_handler == handler).then_some(*component)
+        })
+}
+
+pub fn is_supported(handler: &str) -> bool {
+    is_swupdate(handler) || consent_component(handler).is_some()
+}
+
+// downloads a file of the manifest into dir and verifies its size and hash
+pub async fn download(
+    file: &FileEntity,
+    url: &str,
+    dir: &Path,
+    config: &DownloadConfig,
+) -> Result<PathBuf> {
+    // the file name is given by the cloud and must not point outside of dir
+    ensure!(
+        Path::new(&file.file_name).file_name() == Some(OsStr::new(&file.file_name)),
+        "download: invalid file name \"{}\"",
+        file.file_name
+    );
+
+    let sha256 = file
+        .hashes
+        .get("sha256")
+        .with_context(|| format!("download: sha256 of {} missing", file.file_name))?;
+
+    fs::create_dir_all(dir).context("download: cannot create download dir")?;
+
+    let path = dir.join(&file.file_name);
+
+    info!("download {} to {}", file.file_name, path.display());
+
+    let mut response = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .build()
+        .context("download: cannot create http client")?
+        .get(url)
+        .send()
+        .await
+        .context("download: request failed")?
+        .error_for_status()
+        .context("download: rejected")?;
+
+    let mut output = fs::File::create(&path).context("download: cannot create file")?;
+    let mut size = 0;
+
+    while let Some(chunk) = response.chunk().await.context("download: read failed")? {
+        size += chunk.len() as u64;
+
+        ensure!(
+            size <= file.size_in_bytes,
+            "download: {} exceeds {} bytes",
+            file.file_name,
+            file.size_in_bytes
+        );
+
+        output
+            .write_all(&chunk)
+            .context("download: cannot write file")?;
+    }
+
+    ensure!(
+        size == file.size_in_bytes,
+        "download: {} has {size} instead of {} bytes",
+        file.file_name,
+        file.size_in_bytes
+    );
+
+    let verify_path = path.clone();
+    let sha256 = sha256.clone();
+
+    tokio::task::spawn_blocking(move || verify_sha256(&verify_path, &sha256))
+        .await
+        .context("download: verify task failed")??;
+
+    Ok(path)
+}
+
+fn verify_sha256(path: &Path, expected: &str) -> Result<()> {
+    let expected = base64::decode(expected).context("verify_sha256: invalid hash")?;
+
+    let output = Command::new("sha256sum")
+        .arg(path)
+        .output()
+        .context("verify_sha256: failed to execute sha256sum")?;
+
+    ensure!(
+        output.status.success(),
+        "verify_sha256: sha256sum failed with {}",
+        output.status
+    );
+
+    let actual = String::from_utf8_lossy(&output.stdout);
+    let actual = actual.split_whitespace().next().unwrap_or_default();
+    let expected = expected
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    ensure!(
+        actual == expected,
+        "verify_sha256: hash mismatch of {}",
+        path.display()
+    );
+
+    Ok(())
+}
+
+// installs an image into the inactive partition, blocks until swupdate finished
+pub fn install(image: &Path, arguments: &[String]) -> Result<()> {
+    info!("install {}", image.display());
+
+    let status = Command::new("swupdate")
+        .arg("-v")
+        .arg("-i")
+        .arg(image)
+        .args(arguments)
+        .status()
+        .context("install: failed to execute swupdate")?;
+
+    ensure!(status.success(), "install: swupdate failed with {status}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const SIGNATURE_DIR: &str = "testfiles/signature";
+
+    fn request(manifest: &str, signature: &str) -> DeploymentRequest {
+        serde_json::from_value(json!({
+            "workflow": { "action": ACTION_PROCESS_DEPLOYMENT, "id": "wf" },
+            "updateManifest": manifest,
+            "updateManifestSignature": signature,
+            "fileUrls": { "f1": "http://localhost/f1" }
+        }))
+        .unwrap()
+    }
+
+    fn arguments(arguments: &str) -> Result<Vec<String>> {
+        HandlerProperties {
+            arguments: Some(arguments.to_owned()),
+            ..Default::default()
+        }
+        .swupdate_arguments()
+    }
+
+    // answers a single GET request with body
+    async fn serve_once(body: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/image.swu", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+
+            let header = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                body.len()
+            );
+
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        });
+
+        url
+    }
+
+    #[test]
+    fn parse() {
+        let manifest = parse_manifest(&json!({
+            "manifestVersion": "5",
+            "updateId": { "provider": "omnect", "name": "image", "version": "1.0" },
+            "compatibility": [{ "manufacturer": "conplement-ag", "compatibilityid": "2" }],
+            "instructions": {
+                "steps": [{
+                    "handler": "microsoft/swupdate:2",
+                    "files": ["f1"],
+                    "handlerProperties": { "installedCriteria": "OMNECT 1.0", "reboot": "immediate" }
+                }]
+            },
+            "files": {
+                "f1": { "fileName": "image.swu", "sizeInBytes": 5, "hashes": { "sha256": "x" } }
+            }
+        }).to_string())
+        .unwrap();
+
+        let step = &manifest.instructions.steps[0];
+
+        assert!(is_swupdate(&step.handler));
+        assert_eq!(
+            consent_component("omnect/swupdate_consent:1"),
+            Some("swupdate")
+        );
+        assert!(!is_supported("microsoft/script:1"));
+        assert_eq!(step.handler_properties.reboot, RebootMode::Immediate);
+        assert_eq!(manifest.files["f1"].file_name, "image.swu");
+        assert_eq!(manifest.compatibility[0]["compatibilityid"], "2");
+
+        assert!(parse_manifest(
+            &json!({ "updateId": {}, "instructions": { "steps": [] } }).to_string()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn manifest_must_be_signed() {
+        let root_keys = RootKeys::load(Path::new(SIGNATURE_DIR).join("root-keys.json")).unwrap();
+        let manifest =
+            fs::read_to_string(Path::new(SIGNATURE_DIR).join("update-manifest.json")).unwrap();
+        let signature =
+            fs::read_to_string(Path::new(SIGNATURE_DIR).join("update-manifest.jws")).unwrap();
+
+        let parsed = request(&manifest, &signature).manifest(&root_keys).unwrap();
+        assert_eq!(parsed.files["f1"].file_name, "image.swu");
+
+        let untrusted =
+            fs::read_to_string(Path::new(SIGNATURE_DIR).join("update-manifest-untrusted.jws"))
+                .unwrap();
+        assert!(request(&manifest, &untrusted).manifest(&root_keys).is_err());
+
+        let unsigned: DeploymentRequest = serde_json::from_value(json!({
+            "workflow": { "action": ACTION_PROCESS_DEPLOYMENT, "id": "wf" },
+            "updateManifest": manifest,
+        }))
+        .unwrap();
+        assert!(unsigned
+            .manifest(&root_keys)
+            .unwrap_err()
+            .to_string()
+            .contains("updateManifestSignature missing"));
+    }
+
+    #[test]
+    fn only_software_selection_is_passed_to_swupdate() {
+        assert_eq!(
+            arguments("-e stable,copy2").unwrap(),
+            ["-e", "stable,copy2"]
+        );
+        assert!(arguments(" ").unwrap().is_empty());
+        assert!(HandlerProperties::default()
+            .swupdate_arguments()
+            .unwrap()
+            .is_empty());
+
+        for invalid in [
+            "-P reboot",
+            "-e stable,copy2 -p /bin/sh",
+            "-e -P",
+            "-e stable;reboot",
+            "--select stable,copy2",
+            "-e",
+        ] {
+            assert!(arguments(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn strongest_reboot_mode() {
+        assert_eq!(RebootMode::default().reboot(), Some(Reboot::Required));
+        assert_eq!(
+            [
+                RebootMode::None,
+                RebootMode::Immediate,
+                RebootMode::Required
+            ]
+            .into_iter()
+            .max(),
+            Some(RebootMode::Immediate)
+        );
+        assert_eq!(RebootMode::None.reboot(), None);
+    }
+
+    #[tokio::test]
+    async fn download_and_verify() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut file = FileEntity {
+            file_name: "image.swu".to_owned(),
+            size_in_bytes: 5,
+            // sha256 of "image"
+            hashes: BTreeMap::from([(
+                "sha256".to_owned(),
+                "YQXWzHavQAMl6U1YjOURvlv9u3O0N9xR7KQ5F9ekPj0=".to_owned(),
+            )]),
+        };
+
+        let url = serve_once(b"image").await;
+        let path = download(&file, &url, tmp_dir.path(), &DownloadConfig::default())
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read(path).unwrap(), b"image");
+
+        file.hashes.insert(
+            "sha256".to_owned(),
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_owned(),
+        );
+
+        let url = serve_once(b"image").await;
+        assert!(
+            download(&file, &url, tmp_dir.path(), &DownloadConfig::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_file_name_outside_download_dir() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        for file_name in ["../image.swu", "/image.swu", "..", "dir/image.swu"] {
+            let file = FileEntity {
+                file_name: file_name.to_owned(),
+                size_in_bytes: 5,
+                hashes: BTreeMap::from([("sha256".to_owned(), String::new())]),
+            };
+
+            assert!(download(
+                &file,
+                "http://127.0.0.1:1/image.swu",
+                tmp_dir.path(),
+                &DownloadConfig::default()
+            )
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("invalid file name"));
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use super::{os_release::OsRelease, sw_versions::SwVersions};
+use anyhow::{bail, ensure, Context, Result};
+use log::{debug, info};
+use nix::{
+    libc::fsblkcnt_t,
+    sys::{
+        statvfs::{statvfs, Statvfs},
+        utsname::uname,
+    },
+};
+use serde::Serialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub processor_architecture: String,
+    pub processor_manufacturer: String,
+    // KB as required by the device information interface
+    pub total_memory: u64,
+    // KB as required by the device information interface
+    pub total_storage: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceStatus {
+    // KB
+    pub free_memory: u64,
+    // KB
+    pub free_storage: u64,
+    // seconds
+    pub uptime: u64,
+}
+
+impl DeviceStatus {
+    // uptime always changes, so we only compare memory and storage
+    pub fn differs(&self, other: &DeviceStatus, threshold_percent: u64) -> bool {
+        let differs = |a: u64, b: u64| a.abs_diff(b) * 100 > threshold_percent * a.max(b);
+
+        differs(self.free_memory, other.free_memory)
+            || differs(self.free_storage, other.free_storage)
+    }
+}
+
+pub struct DeviceInfoCollector {
+    proc_dir: PathBuf,
+    mount_points: Vec<PathBuf>,
+}
+
+impl DeviceInfoCollector {
+    pub fn new(
+        proc_dir: impl Into<PathBuf>,
+        mount_points: impl IntoIterator<Item = impl Into<PathBuf>>,
+    ) -> Self {
+        DeviceInfoCollector {
+            proc_dir: proc_dir.into(),
+            mount_points: mount_points.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn collect(&self) -> Result<DeviceInfo> {
+        let device_info = DeviceInfo {
+            processor_architecture: processor_architecture()?,
+            processor_manufacturer: self.processor_manufacturer()?,
+            total_memory: self.meminfo("MemTotal")?,
+            total_storage: self.total_storage()?,
+        };
+
+        debug!("collected device info: {device_info:?}");
+
+        Ok(device_info)
+    }
+
+    fn processor_manufacturer(&self) -> Result<String> {
+        let cpuinfo = fs::read_to_string(self.proc_dir.join("cpuinfo"))
+            .context("processor_manufacturer: cannot read cpuinfo")?;
+
+        // x86
+        if let Some(vendor_id) = cpuinfo_value(&cpuinfo, "vendor_id") {
+            return Ok(vendor_id.to_owned());
+        }
+
+        // arm
+        if let Some(implementer) = cpuinfo_value(&cpuinfo, "CPU implementer") {
+            let manufacturer = match implementer.to_lowercase().as_str() {
+                "0x41" => "ARM",
+                "0x42" => "Broadcom",
+                "0x43" => "Cavium",
+                "0x48" => "HiSilicon",
+                "0x4e" => "NVIDIA",
+                "0x51" => "Qualcomm",
+                "0x53" => "Samsung",
+                "0x56" => "Marvell",
+                "0x61" => "Apple",
+                "0x69" => "Intel",
+                _ => implementer,
+            };
+
+            return Ok(manufacturer.to_owned());
+        }
+
+        Ok("unknown".to_owned())
+    }
+
+    pub fn collect_status(&self) -> Result<DeviceStatus> {
+        let device_status = DeviceStatus {
+            free_memory: self.meminfo("MemAvailable")?,
+            free_storage: self.storage(|stat| stat.blocks_available())?,
+            uptime: self.uptime()?,
+        };
+
+        debug!("collected device status: {device_status:?}");
+
+        Ok(device_status)
+    }
+
+    pub fn meminfo(&self, key: &str) -> Result<u64> {
+        let meminfo = fs::read_to_string(self.proc_dir.join("meminfo"))
+            .context("meminfo: cannot read meminfo")?;
+
+        // e.g. "MemTotal:        3884332 kB"
+        let value = meminfo
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix(key)
+                    .and_then(|value| value.strip_prefix(':'))
+            })
+            .with_context(|| format!("meminfo: {key} missing"))?;
+
+        let (value, unit) = value
+            .trim()
+            .split_once(' ')
+            .with_context(|| format!("meminfo: {key}: unit missing"))?;
+
+        ensure!(
+            unit.trim() == "kB",
+            "meminfo: {key}: unexpected unit {unit}"
+        );
+
+        value
+            .parse()
+            .with_context(|| format!("meminfo: {key}: invalid value {value}"))
+    }
+
+    pub fn total_storage(&self) -> Result<u64> {
+        self.storage(|stat| stat.blocks())
+    }
+
+    // block counts and sizes are only 32 bit wide on some targets
+    #[allow(clippy::useless_conversion)]
+    fn storage(&self, blocks: impl Fn(&Statvfs) -> fsblkcnt_t) -> Result<u64> {
+        self.mount_points.iter().try_fold(0, |sum, mount_point| {
+            let stat = statvfs(mount_point.as_path())
+                .with_context(|| format!("storage: statvfs {}", mount_point.display()))?;
+
+            Ok(sum + u64::from(blocks(&stat)) * u64::from(stat.fragment_size()) / 1024)
+        })
+    }
+
+    fn uptime(&self) -> Result<u64> {
+        let uptime = fs::read_to_string(self.proc_dir.join("uptime"))
+            .context("uptime: cannot read uptime")?;
+
+        // e.g. "350735.47 234388.90", the first value is the uptime in seconds
+        let uptime: f64 = uptime
+            .split_whitespace()
+            .next()
+            .context("uptime: value missing")?
+            .parse()
+            .context("uptime: invalid value")?;
+
+        Ok(uptime as u64)
+    }
+}
+
+/*
+ * sw-versions is preferred if present. Otherwise the os image is described by os-release:
+ * - name: OMNECT_IMAGE_NAME, then NAME, then ID
+ * - version: OMNECT_IMAGE_VERSION, then VERSION_ID extended by BUILD_ID, e.g. "4.0.17.123456"
+ */
+pub fn sw_versions(
+    sw_versions_path: impl AsRef<Path>,
+    os_release_path: impl AsRef<Path>,
+) -> Result<SwVersions> {
+    if sw_versions_path.as_ref().exists() {
+        return SwVersions::load(sw_versions_path);
+    }
+
+    info!(
+        "{} missing: fall back to {}",
+        sw_versions_path.as_ref().display(),
+        os_release_path.as_ref().display()
+    );
+
+    let os_release = OsRelease::load(os_release_path)?;
+
+    let name = ["OMNECT_IMAGE_NAME", "NAME", "ID"]
+        .iter()
+        .find_map(|key| os_release.get(key))
+        .context("sw_versions: os-release: os name missing")?;
+
+    let version = match (
+        os_release.get("OMNECT_IMAGE_VERSION"),
+        os_release.get("VERSION_ID"),
+        os_release.get("BUILD_ID"),
+    ) {
+        (Some(version), _, _) => version.to_owned(),
+        (None, Some(version), Some(build)) => format!("{version}.{build}"),
+        (None, Some(version), None) => version.to_owned(),
+        (None, None, _) => bail!("sw_versions: os-release: os version missing"),
+    };
+
+    Ok(SwVersions::from_os(name, version))
+}
+
+fn processor_architecture() -> Result<String> {
+    uname()
+        .context("processor_architecture: uname failed")?
+        .machine()
+        .to_str()
+        .map(str::to_owned)
+        .context("processor_architecture: invalid machine")
+}
+
+fn cpuinfo_value<'a>(cpuinfo: &'a str, key: &str) -> Option<&'a str> {
+    cpuinfo.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        (k.trim() == key).then(|| v.trim())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROC_DIR: &str = "testfiles/device-info/proc";
+    const ETC_DIR: &str = "testfiles/device-info/etc";
+
+    #[test]
+    fn collect_from_fixtures() {
+        let device_info = DeviceInfoCollector::new(PROC_DIR, ["/"]).collect().unwrap();
+
+        assert!(!device_info.processor_architecture.is_empty());
+        assert_eq!(device_info.processor_manufacturer, "ARM");
+        assert_eq!(device_info.total_memory, 3884332);
+        assert!(device_info.total_storage > 0);
+    }
+
+    #[test]
+    fn meminfo_missing_key() {
+        let collector = DeviceInfoCollector::new(PROC_DIR, ["/"]);
+
+        assert_eq!(collector.meminfo("MemAvailable").unwrap(), 3560280);
+        assert!(collector.meminfo("HugePages_Total").is_err());
+    }
+
+    #[test]
+    fn collect_status_from_fixtures() {
+        let device_status = DeviceInfoCollector::new(PROC_DIR, ["/"])
+            .collect_status()
+            .unwrap();
+
+        assert_eq!(device_status.free_memory, 3560280);
+        assert_eq!(device_status.uptime, 350735);
+    }
+
+    #[test]
+    fn status_differs_beyond_threshold() {
+        let status = DeviceStatus {
+            free_memory: 1000,
+            free_storage: 1000,
+            uptime: 10,
+        };
+
+        let mut other = status.clone();
+        other.uptime = 20;
+        other.free_memory = 1050;
+        assert!(!status.differs(&other, 10));
+
+        other.free_storage = 800;
+        assert!(status.differs(&other, 10));
+    }
+
+    #[test]
+    fn storage_is_summed_up() {
+        let single = DeviceInfoCollector::new(PROC_DIR, ["/"]);
+        let double = DeviceInfoCollector::new(PROC_DIR, ["/", "/"]);
+
+        assert_eq!(
+            2 * single.total_storage().unwrap(),
+            double.total_storage().unwrap()
+        );
+    }
+
+    #[test]
+    fn sw_versions_precedence() {
+        let etc = Path::new(ETC_DIR);
+
+        let versions = sw_versions(etc.join("sw-versions"), etc.join("os-release-omnect")).unwrap();
+        assert_eq!(versions.os(), ("OMNECT-gateway-devel", "4.0.17.123456"));
+        assert_eq!(versions.components().count(), 2);
+
+        let versions = sw_versions(etc.join("missing"), etc.join("os-release-omnect")).unwrap();
+        assert_eq!(versions.os(), ("OMNECT-gateway-devel", "4.0.17.123456"));
+        assert_eq!(versions.components().count(), 1);
+
+        let versions = sw_versions(etc.join("missing"), etc.join("os-release-generic")).unwrap();
+        assert_eq!(
+            versions.os(),
+            ("Poky (Yocto Project Reference Distro)", "4.0.17")
+        );
+
+        assert!(sw_versions(etc.join("missing"), etc.join("missing")).is_err());
+    }
+}
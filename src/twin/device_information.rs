@@ -0,0 +1,182 @@
+use super::{
+    device_info::{self, DeviceInfoCollector, DeviceStatus},
+    du_config::DuConfig,
+    feature::{self, Event, EventStream, Feature},
+    service_config::{self, DeviceInfoConfig},
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use log::{debug, info, warn};
+use notify::RecommendedWatcher;
+use serde::Serialize;
+use serde_json::json;
+use tokio::{sync::mpsc::Sender, time::Duration};
+
+#[derive(PartialEq, Serialize)]
+struct DeviceInformationComponent {
+    __t: String,
+    manufacturer: String,
+    model: String,
+    osName: String,
+    swVersion: String,
+    processorArchitecture: String,
+    processorManufacturer: String,
+    totalMemory: u64,
+    totalStorage: u64,
+}
+
+impl DeviceInformationComponent {
+    fn new(du_config: &DuConfig, collector: &DeviceInfoCollector) -> Result<Self> {
+        let paths = service_config::paths();
+        let sw_versions = device_info::sw_versions(&paths.sw_versions, &paths.os_release)?;
+        let (os_name, sw_version) = sw_versions.os();
+        let collected = collector.collect()?;
+
+        Ok(DeviceInformationComponent {
+            __t: "c".to_owned(),
+            manufacturer: du_config.manufacturer.clone(),
+            model: du_config.model.clone(),
+            osName: os_name.to_owned(),
+            swVersion: sw_version.to_owned(),
+            processorArchitecture: collected.processor_architecture,
+            processorManufacturer: collected.processor_manufacturer,
+            totalMemory: collected.total_memory,
+            totalStorage: collected.total_storage,
+        })
+    }
+}
+
+// the deviceInformation component and the periodic device status
+pub struct DeviceInformation {
+    tx_reported_properties: Sender<serde_json::Value>,
+    config: DeviceInfoConfig,
+    collector: DeviceInfoCollector,
+    component: DeviceInformationComponent,
+    last_device_status: Option<DeviceStatus>,
+    file_watcher: Option<RecommendedWatcher>,
+}
+
+impl DeviceInformation {
+    pub fn new(
+        du_config: &DuConfig,
+        tx_reported_properties: Sender<serde_json::Value>,
+    ) -> Result<Self> {
+        let config = service_config::get().device_info.clone();
+        let collector = DeviceInfoCollector::new("/proc", &config.mount_points);
+
+        Ok(DeviceInformation {
+            tx_reported_properties,
+            config,
+            component: DeviceInformationComponent::new(du_config, &collector)?,
+            collector,
+            last_device_status: None,
+            file_watcher: None,
+        })
+    }
+
+    /*
+     * Re-reads du-config.json, sw-versions and os-release. Returns true if anything we report
+     * changed. If one of the files is invalid we keep the current properties.
+     */
+    fn reload(&mut self) -> Result<bool> {
+        let du_config = DuConfig::load(&service_config::paths().du_config)?;
+        let component = DeviceInformationComponent::new(&du_config, &self.collector)?;
+
+        if component == self.component {
+            debug!("reload: device information unchanged");
+            return Ok(false);
+        }
+
+        info!("reload: device information changed");
+
+        self.component = component;
+
+        Ok(true)
+    }
+
+    async fn report_device_info(&self) -> Result<()> {
+        self.tx_reported_properties
+            .send(json!({
+                "deviceInformation": serde_json::to_value(&self.component)?
+            }))
+            .await
+            .context("report_device_info: report_impl")
+    }
+
+    // only reports if free memory or storage changed beyond threshold in order to avoid throttling
+    async fn report_device_status(&mut self) -> Result<()> {
+        let threshold_percent = self.config.status_threshold_percent;
+        let device_status = self.collector.collect_status()?;
+
+        if self
+            .last_device_status
+            .as_ref()
+            .is_some_and(|last| !last.differs(&device_status, threshold_percent))
+        {
+            return Ok(());
+        }
+
+        self.tx_reported_properties
+            .send(json!({
+                "device_status": serde_json::to_value(&device_status)?
+            }))
+            .await
+            .context("report_device_status: report_impl")?;
+
+        self.last_device_status = Some(device_status);
+
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Feature for DeviceInformation {
+    fn name(&self) -> &'static str {
+        "device_information"
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    async fn report_initial_state(&mut self) -> Result<()> {
+        self.report_device_info().await
+    }
+
+    fn event_stream(&mut self) -> Result<Option<EventStream>> {
+        let paths = service_config::paths();
+        let (file_watcher, file_changed) = feature::file_stream(&[
+            paths.du_config.as_path(),
+            paths.sw_versions.as_path(),
+            paths.os_release.as_path(),
+        ])?;
+
+        self.file_watcher = Some(file_watcher);
+
+        let stream = match self.config.status_interval_secs {
+            0 => file_changed,
+            secs => stream::select(
+                file_changed,
+                feature::interval_stream(Duration::from_secs(secs)),
+            )
+            .boxed(),
+        };
+
+        Ok(Some(stream))
+    }
+
+    async fn handle_event(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Interval => self.report_device_status().await,
+            Event::FileChanged => match self.reload() {
+                Ok(true) => self.report_device_info().await,
+                Ok(false) => Ok(()),
+                Err(e) => {
+                    warn!("keep current device information, since reload failed: {e:#}");
+                    Ok(())
+                }
+            },
+        }
+    }
+}
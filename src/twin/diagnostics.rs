@@ -0,0 +1,511 @@
+use super::{
+    feature::Feature,
+    service_config::{self, DiagnosticsConfig, DownloadConfig},
+};
+use anyhow::{bail, ensure, Context, Result};
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+use tokio::sync::{mpsc::Sender, watch};
+
+const LAST_OPERATION_FILE: &str = "diagnostics-operation";
+const ARCHIVE_NAME: &str = "diagnostics.tar.gz";
+const RESULT_SUCCESS: &str = "200";
+const RESULT_FAILURE: &str = "500";
+
+/*
+ * desired "diagnosticInformation" component:
+ * {
+ *     "__t": "c",
+ *     "service": {
+ *         "operationId": "...",
+ *         "sasUrl": "https://<account>.blob.core.windows.net/<container>?<sas token>"
+ *     }
+ * }
+ */
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LogUploadRequest {
+    operation_id: String,
+    sas_url: String,
+}
+
+pub struct Diagnostics {
+    tx_reported_properties: Sender<serde_json::Value>,
+    // the upload uses the connect timeout of downloads
+    rx_download_config: watch::Receiver<DownloadConfig>,
+    last_operation_id: Option<String>,
+}
+
+impl Diagnostics {
+    pub fn new(
+        tx_reported_properties: Sender<serde_json::Value>,
+        rx_download_config: watch::Receiver<DownloadConfig>,
+    ) -> Self {
+        let last_operation_id = fs::read_to_string(last_operation_path())
+            .ok()
+            .map(|id| id.trim().to_owned())
+            .filter(|id| !id.is_empty());
+
+        Diagnostics {
+            tx_reported_properties,
+            rx_download_config,
+            last_operation_id,
+        }
+    }
+
+    // every operation is only run once, the result is reported when the upload finished
+    fn update(&mut self, desired: &serde_json::Value) -> Result<()> {
+        let Some(service) = desired.get("service").filter(|service| !service.is_null()) else {
+            return Ok(());
+        };
+
+        let request: LogUploadRequest = serde_json::from_value(service.clone())
+            .context("diagnostics: invalid log upload request")?;
+
+        validate_operation_id(&request.operation_id)?;
+
+        if self.last_operation_id.as_deref() == Some(request.operation_id.as_str()) {
+            debug!("log upload {} already handled", request.operation_id);
+            return Ok(());
+        }
+
+        // a restart must not repeat the operation, e.g. with an expired sas url
+        let path = last_operation_path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("diagnostics: cannot create state dir")?;
+        }
+        fs::write(path, &request.operation_id)
+            .context("diagnostics: cannot persist operation id")?;
+        self.last_operation_id = Some(request.operation_id.clone());
+
+        let tx_reported_properties = self.tx_reported_properties.clone();
+        let config = service_config::get().diagnostics.clone();
+        let connect_timeout =
+            Duration::from_secs(self.rx_download_config.borrow().connect_timeout_secs);
+
+        tokio::spawn(async move {
+            info!("log upload {} started", request.operation_id);
+
+            let result = upload_logs(&request, config, connect_timeout).await;
+
+            match &result {
+                Ok(()) => info!("log upload {} succeeded", request.operation_id),
+                Err(e) => error!("log upload {}: {e:#}", request.operation_id),
+            }
+
+            if let Err(e) =
+                report_result(&tx_reported_properties, &request.operation_id, &result).await
+            {
+                error!("log upload {}: {e:#}", request.operation_id);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Feature for Diagnostics {
+    fn name(&self) -> &'static str {
+        "diagnostics"
+    }
+
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn desired_properties(&self) -> &'static [&'static str] {
+        &["diagnosticInformation"]
+    }
+
+    async fn handle_desired(&mut self, property: &str, desired: &serde_json::Value) -> Result<()> {
+        match property {
+            "diagnosticInformation" => self.update(desired),
+            _ => bail!("unknown desired property \"{property}\""),
+        }
+    }
+}
+
+async fn upload_logs(
+    request: &LogUploadRequest,
+    config: DiagnosticsConfig,
+    connect_timeout: Duration,
+) -> Result<()> {
+    let paths = service_config::paths();
+    let work_dir = paths
+        .download_dir
+        .join(format!("diagnostics-{}", request.operation_id));
+    let state_dir = paths.state_dir.clone();
+    let timeout = Duration::from_secs(config.upload_timeout_secs);
+
+    let archive = tokio::task::spawn_blocking(move || {
+        let archive = collect(&work_dir, &state_dir, &config);
+
+        if let Err(e) = fs::remove_dir_all(&work_dir) {
+            warn!("diagnostics: cannot remove {}: {e}", work_dir.display());
+        }
+
+        archive
+    })
+    .await
+    .context("upload_logs: collect task failed")??;
+
+    upload(
+        &request.sas_url,
+        &request.operation_id,
+        archive,
+        timeout,
+        connect_timeout,
+    )
+    .await
+}
+
+// returns the gzip compressed tar archive of all log sources
+fn collect(work_dir: &Path, state_dir: &Path, config: &DiagnosticsConfig) -> Result<Vec<u8>> {
+    if work_dir.exists() {
+        fs::remove_dir_all(work_dir).context("collect: cannot clean work dir")?;
+    }
+
+    fs::create_dir_all(work_dir).context("collect: cannot create work dir")?;
+
+    let mut entries = vec![];
+
+    for unit in &config.journal_units {
+        let name = format!("journal-{unit}.log");
+
+        // a failing source shouldn't prevent the upload of the others
+        let journal = journal(unit, config.journal_lines).unwrap_or_else(|e| {
+            warn!("collect: {e:#}");
+            format!("{e:#}\n").into_bytes()
+        });
+
+        fs::write(work_dir.join(&name), journal).context("collect: cannot write journal")?;
+        entries.push(PathBuf::from(name));
+    }
+
+    let state_files = fs::read_dir(state_dir)
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect()
+        })
+        .unwrap_or_else(|e| {
+            warn!("collect: cannot read {}: {e}", state_dir.display());
+            vec![]
+        });
+
+    let mut files = vec![];
+
+    for file in config.files.iter().chain(state_files.iter()) {
+        match fs::canonicalize(file) {
+            Ok(file) => files.push(file),
+            Err(e) => warn!("collect: skip {}: {e}", file.display()),
+        }
+    }
+
+    let mut archive = work_dir.as_os_str().to_owned();
+    archive.push(".tar.gz");
+    let archive = PathBuf::from(archive);
+
+    // absolute paths are archived relative to /
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(work_dir)
+        .args(&entries)
+        .arg("-C")
+        .arg("/")
+        .args(files.iter().filter_map(|file| file.strip_prefix("/").ok()))
+        .status()
+        .context("collect: failed to execute tar")?;
+
+    let content = fs::read(&archive).context("collect: cannot read archive");
+
+    let _ = fs::remove_file(&archive);
+
+    ensure!(status.success(), "collect: tar failed with {status}");
+
+    content
+}
+
+fn journal(unit: &str, lines: u32) -> Result<Vec<u8>> {
+    let output = Command::new("journalctl")
+        .args(["--no-pager", "-o", "short-iso", "-n"])
+        .arg(lines.to_string())
+        .arg("-u")
+        .arg(unit)
+        .output()
+        .context("journal: failed to execute journalctl")?;
+
+    ensure!(
+        output.status.success(),
+        "journal: journalctl -u {unit} failed with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    Ok(output.stdout)
+}
+
+/*
+ * The operation id is part of a local path and of the blob url, so we only accept
+ * characters which need neither escaping nor encoding.
+ */
+fn validate_operation_id(operation_id: &str) -> Result<()> {
+    ensure!(
+        !operation_id.is_empty()
+            && operation_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "diagnostics: invalid operation id \"{operation_id}\""
+    );
+
+    Ok(())
+}
+
+// the sas token grants access to the container, so it must not end up in the journal
+pub fn redact_sas_url(desired: &mut serde_json::Value) {
+    match desired {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                if key == "sasUrl" && value.is_string() {
+                    *value = json!("<redacted>");
+                } else {
+                    redact_sas_url(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_sas_url),
+        _ => {}
+    }
+}
+
+// the archive is stored as <container>/<operation id>/diagnostics.tar.gz
+fn blob_url(sas_url: &str, operation_id: &str) -> Result<String> {
+    let (container, token) = sas_url
+        .split_once('?')
+        .filter(|(_, token)| !token.is_empty())
+        .context("blob_url: sas token missing")?;
+
+    Ok(format!(
+        "{}/{operation_id}/{ARCHIVE_NAME}?{token}",
+        container.trim_end_matches('/')
+    ))
+}
+
+async fn upload(
+    sas_url: &str,
+    operation_id: &str,
+    archive: Vec<u8>,
+    timeout: Duration,
+    connect_timeout: Duration,
+) -> Result<()> {
+    let url = blob_url(sas_url, operation_id)?;
+
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .connect_timeout(connect_timeout)
+        .build()
+        .context("upload: cannot create http client")?
+        .put(url.as_str())
+        .header("x-ms-blob-type", "BlockBlob")
+        .header("content-type", "application/gzip")
+        .body(archive)
+        .send()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .context("upload: request failed")?
+        .error_for_status()
+        .map_err(reqwest::Error::without_url)
+        .context("upload: rejected")?;
+
+    Ok(())
+}
+
+async fn report_result(
+    tx_reported_properties: &Sender<serde_json::Value>,
+    operation_id: &str,
+    result: &Result<()>,
+) -> Result<()> {
+    let (result_code, result_details) = match result {
+        Ok(()) => (RESULT_SUCCESS, String::new()),
+        Err(e) => (RESULT_FAILURE, format!("{e:#}")),
+    };
+
+    tx_reported_properties
+        .send(json!({
+            "diagnosticInformation": {
+                "__t": "c",
+                "agent": {
+                    "operationId": operation_id,
+                    "resultCode": result_code,
+                    "extendedResultCodes": "0",
+                    "resultDetails": result_details
+                }
+            }
+        }))
+        .await
+        .context("report_result: report_impl")
+}
+
+fn last_operation_path() -> PathBuf {
+    service_config::paths().state_dir.join(LAST_OPERATION_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // answers a single request with status and returns the raw request
+    async fn serve_once(status: &'static str) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0u8; 4096];
+
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+
+                let text = String::from_utf8_lossy(&request).to_lowercase();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map_or(0, |length| length.trim().parse().unwrap());
+
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+
+                if n == 0 {
+                    break;
+                }
+            }
+
+            stream
+                .write_all(format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+
+            request
+        });
+
+        (url, server)
+    }
+
+    #[test]
+    fn operation_id_characters() {
+        assert!(validate_operation_id("4f3a-b2_C9").is_ok());
+
+        for operation_id in ["", "..", "../state", "a/b", "a.b", "a b", "a?b", "ä"] {
+            assert!(validate_operation_id(operation_id).is_err());
+        }
+    }
+
+    #[test]
+    fn sas_url_is_redacted() {
+        let mut desired = json!({
+            "desired": {
+                "diagnosticInformation": {
+                    "service": { "operationId": "op", "sasUrl": "https://blob/logs?sig=secret" }
+                },
+                "$version": 2
+            }
+        });
+
+        redact_sas_url(&mut desired);
+
+        assert_eq!(
+            desired["desired"]["diagnosticInformation"]["service"],
+            json!({ "operationId": "op", "sasUrl": "<redacted>" })
+        );
+        assert!(!desired.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn blob_url_of_operation() {
+        assert_eq!(
+            blob_url("https://a.blob.core.windows.net/logs/?sv=1&sig=x", "op").unwrap(),
+            "https://a.blob.core.windows.net/logs/op/diagnostics.tar.gz?sv=1&sig=x"
+        );
+        assert!(blob_url("https://a.blob.core.windows.net/logs", "op").is_err());
+    }
+
+    #[test]
+    fn collect_archive() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = DiagnosticsConfig {
+            journal_units: vec![],
+            files: vec!["testfiles/du-config.json".into(), "not-existing".into()],
+            ..Default::default()
+        };
+
+        let archive = collect(
+            &tmp_dir.path().join("work.1"),
+            Path::new("testfiles/device-info/etc"),
+            &config,
+        )
+        .unwrap();
+
+        // gzip magic
+        assert_eq!(archive[..2], [0x1f, 0x8b]);
+        assert!(!tmp_dir.path().join("work.1.tar.gz").exists());
+        assert!(!tmp_dir.path().join("work.tar.gz").exists());
+    }
+
+    #[tokio::test]
+    async fn upload_to_local_server() {
+        let (url, server) = serve_once("201 Created").await;
+
+        upload(
+            &format!("{url}/logs?sv=1&sig=x"),
+            "op",
+            b"archive".to_vec(),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        let request = String::from_utf8(server.await.unwrap()).unwrap();
+
+        assert!(request.starts_with("PUT /logs/op/diagnostics.tar.gz?sv=1&sig=x HTTP/1.1"));
+        assert!(request.to_lowercase().contains("x-ms-blob-type: blockblob"));
+        assert!(request.ends_with("archive"));
+    }
+
+    #[tokio::test]
+    async fn upload_rejected() {
+        let (url, server) = serve_once("403 Forbidden").await;
+
+        assert!(upload(
+            &format!("{url}/logs?sig=x"),
+            "op",
+            b"archive".to_vec(),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .await
+        .is_err());
+
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use log::info;
+use nix::unistd::{geteuid, User};
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, path::Path};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum SchemaVersion {
+    #[serde(rename = "1.0")]
+    V1_0,
+    #[serde(rename = "1.1")]
+    V1_1,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum IotHubProtocol {
+    #[serde(rename = "mqtt")]
+    Mqtt,
+    #[serde(rename = "mqtt/ws")]
+    MqttWs,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum ConnectionType {
+    #[serde(rename = "AIS")]
+    Ais,
+    #[serde(rename = "string")]
+    String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionSource {
+    pub connection_type: ConnectionType,
+    #[serde(default)]
+    pub connection_data: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientKind {
+    Device,
+    Module,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentConfig {
+    pub name: String,
+    pub runas: String,
+    pub connection_source: ConnectionSource,
+    pub manufacturer: String,
+    pub model: String,
+    #[serde(default)]
+    pub additional_device_properties: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DuConfig {
+    pub schema_version: SchemaVersion,
+    #[serde(default)]
+    pub adu_shell_trusted_users: Vec<String>,
+    // only supported by schema version 1.1, device update agent defaults to mqtt
+    pub iot_hub_protocol: Option<IotHubProtocol>,
+    #[serde(default = "default_compat_property_names")]
+    pub compat_property_names: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub agents: Vec<AgentConfig>,
+    // not part of the device update agent schema
+    #[serde(default)]
+    pub health_check_units: Vec<String>,
+}
+
+// additional device properties are reported next to these in deviceProperties
+const RESERVED_DEVICE_PROPERTIES: [&str; 4] =
+    ["manufacturer", "model", "contractModelId", "aduVer"];
+
+fn default_compat_property_names() -> String {
+    "manufacturer,model".to_owned()
+}
+
+// the agent name can be configured via env, otherwise the name of our binary is used
+pub fn agent_name() -> Result<String> {
+    if let Ok(name) = env::var("AGENT_NAME") {
+        return Ok(name);
+    }
+
+    env::current_exe()
+        .context("agent_name: cannot get current executable")?
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_owned)
+        .context("agent_name: cannot get name of current executable")
+}
+
+impl ConnectionSource {
+    /*
+     * The identity service provides the module identity of the agent. A connection string
+     * belongs to a module identity if it contains a ModuleId, otherwise to a device identity.
+     */
+    pub fn client_kind(&self) -> ClientKind {
+        match self.connection_type {
+            ConnectionType::Ais => ClientKind::Module,
+            ConnectionType::String => {
+                if connection_string_value(&self.connection_data, "ModuleId").is_some() {
+                    ClientKind::Module
+                } else {
+                    ClientKind::Device
+                }
+            }
+        }
+    }
+}
+
+// e.g. "HostName=my-hub.azure-devices.net;DeviceId=my-device;SharedAccessKey=..."
+fn connection_string_value<'a>(connection_string: &'a str, key: &str) -> Option<&'a str> {
+    connection_string.split(';').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        (k.trim() == key && !v.trim().is_empty()).then(|| v.trim())
+    })
+}
+
+impl AgentConfig {
+    pub fn ensure_runas(&self) -> Result<()> {
+        // tests and local development usually don't run as the configured user
+        if cfg!(feature = "mock") {
+            return Ok(());
+        }
+
+        let user = User::from_uid(geteuid())
+            .context("ensure_runas: cannot get user")?
+            .context("ensure_runas: unknown user")?;
+
+        ensure!(
+            user.name == self.runas,
+            "agent \"{}\" is configured to run as \"{}\", but runs as \"{}\"",
+            self.name,
+            self.runas,
+            user.name
+        );
+
+        Ok(())
+    }
+}
+
+impl DuConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path).context("cannot read du-config.json")?;
+
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let du_config: DuConfig =
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(content))
+                .map_err(|e| anyhow!("du-config.json: {}: {}", e.path(), e.inner()))?;
+
+        du_config.validate()?;
+
+        Ok(du_config)
+    }
+
+    pub fn agent(&self, name: &str) -> Result<&AgentConfig> {
+        let mut agents = self.agents.iter().filter(|agent| agent.name == name);
+
+        let agent = agents
+            .next()
+            .with_context(|| format!("du-config.json: agents: no agent named \"{name}\""))?;
+
+        ensure!(
+            agents.next().is_none(),
+            "du-config.json: agents: multiple agents named \"{name}\""
+        );
+
+        info!("use agent \"{name}\" running as \"{}\"", agent.runas);
+
+        Ok(agent)
+    }
+
+    // resolves all properties listed in compatPropertyNames for the given agent
+    pub fn compat_properties(&self, agent: &AgentConfig) -> Result<BTreeMap<String, String>> {
+        self.compat_property_names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let value = match name {
+                    "manufacturer" => Some(&agent.manufacturer),
+                    "model" => Some(&agent.model),
+                    _ => agent.additional_device_properties.get(name),
+                };
+
+                let value = value.with_context(|| {
+                    format!(
+                        "du-config.json: compatPropertyNames: \"{name}\" is no property of agent \"{}\"",
+                        agent.name
+                    )
+                })?;
+
+                Ok((name.to_owned(), value.clone()))
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.iot_hub_protocol.is_none() || self.schema_version == SchemaVersion::V1_1,
+            "du-config.json: iotHubProtocol: not supported by schemaVersion 1.0"
+        );
+
+        ensure_not_empty("manufacturer", &self.manufacturer)?;
+        ensure_not_empty("model", &self.model)?;
+
+        ensure!(
+            !self.agents.is_empty(),
+            "du-config.json: agents: at least one agent required"
+        );
+
+        for (i, agent) in self.agents.iter().enumerate() {
+            ensure_not_empty(&format!("agents[{i}].name"), &agent.name)?;
+            ensure_not_empty(&format!("agents[{i}].runas"), &agent.runas)?;
+            ensure_not_empty(&format!("agents[{i}].manufacturer"), &agent.manufacturer)?;
+            ensure_not_empty(&format!("agents[{i}].model"), &agent.model)?;
+
+            for name in agent.additional_device_properties.keys() {
+                ensure!(
+                    !RESERVED_DEVICE_PROPERTIES.contains(&name.as_str()),
+                    "du-config.json: agents[{i}].additionalDeviceProperties.{name}: reserved property name"
+                );
+            }
+
+            if agent.connection_source.connection_type == ConnectionType::String {
+                let connection_data = &agent.connection_source.connection_data;
+
+                for key in ["HostName", "DeviceId"] {
+                    ensure!(
+                        connection_string_value(connection_data, key).is_some(),
+                        "du-config.json: agents[{i}].connectionSource.connectionData: {key} missing"
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn ensure_not_empty(field: &str, value: &str) -> Result<()> {
+    ensure!(
+        !value.trim().is_empty(),
+        "du-config.json: {field}: must not be empty"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection_source(
+        connection_type: ConnectionType,
+        connection_data: &str,
+    ) -> ConnectionSource {
+        ConnectionSource {
+            connection_type,
+            connection_data: connection_data.to_owned(),
+        }
+    }
+
+    fn parse_error(du_config: &str) -> String {
+        format!("{:#}", DuConfig::parse(du_config).unwrap_err())
+    }
+
+    #[test]
+    fn parse_schema_versions() {
+        let du_config = std::fs::read_to_string("testfiles/du-config.json").unwrap();
+        let parsed = DuConfig::parse(&du_config).unwrap();
+
+        assert_eq!(parsed.schema_version, SchemaVersion::V1_1);
+        assert_eq!(parsed.iot_hub_protocol, Some(IotHubProtocol::Mqtt));
+        assert_eq!(
+            parsed.agents[0].additional_device_properties["compatibilityid"],
+            "2"
+        );
+
+        let v1_0 = du_config.replace(r#""schemaVersion": "1.1""#, r#""schemaVersion": "1.0""#);
+        assert!(parse_error(&v1_0).contains("iotHubProtocol"));
+
+        let v1_0 = v1_0.replace(r#""iotHubProtocol": "mqtt","#, "");
+        assert_eq!(DuConfig::parse(&v1_0).unwrap().iot_hub_protocol, None);
+
+        let v2_0 = du_config.replace(r#""schemaVersion": "1.1""#, r#""schemaVersion": "2.0""#);
+        assert!(parse_error(&v2_0).starts_with("du-config.json: schemaVersion: "));
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let du_config = std::fs::read_to_string("testfiles/du-config.json").unwrap();
+
+        let missing = du_config.replace(r#""model": "omnect-raspberrypi4-64-gateway-devel","#, "");
+        assert!(
+            parse_error(&missing).starts_with("du-config.json: agents[0]: missing field `model`")
+        );
+
+        let empty = du_config.replace(r#""runas": "adu""#, r#""runas": " ""#);
+        assert_eq!(
+            parse_error(&empty),
+            "du-config.json: agents[0].runas: must not be empty"
+        );
+
+        let reserved = du_config.replace(r#""compatibilityid": "2""#, r#""aduVer": "2""#);
+        assert!(parse_error(&reserved)
+            .contains("agents[0].additionalDeviceProperties.aduVer: reserved property name"));
+
+        let no_agents = DuConfig::parse(
+            r#"{"schemaVersion": "1.0", "manufacturer": "m", "model": "m", "agents": []}"#,
+        );
+        assert!(format!("{:#}", no_agents.unwrap_err()).contains("at least one agent required"));
+    }
+
+    #[test]
+    fn client_kind_by_connection_source() {
+        assert_eq!(
+            connection_source(ConnectionType::Ais, "").client_kind(),
+            ClientKind::Module
+        );
+        assert_eq!(
+            connection_source(
+                ConnectionType::String,
+                "HostName=hub.azure-devices.net;DeviceId=device;SharedAccessKey=key"
+            )
+            .client_kind(),
+            ClientKind::Device
+        );
+        assert_eq!(
+            connection_source(
+                ConnectionType::String,
+                "HostName=hub.azure-devices.net;DeviceId=device;ModuleId=AducIotAgent;SharedAccessKey=key"
+            )
+            .client_kind(),
+            ClientKind::Module
+        );
+    }
+
+    #[test]
+    fn connection_data_required_for_string() {
+        let du_config = std::fs::read_to_string("testfiles/du-config.json").unwrap();
+        assert!(DuConfig::parse(&du_config).is_ok());
+
+        let invalid = du_config.replace(
+            r#""connectionType": "AIS""#,
+            r#""connectionType": "string""#,
+        );
+        assert!(DuConfig::parse(&invalid).is_err());
+
+        let valid = invalid.replace(
+            r#""connectionData": """#,
+            r#""connectionData": "HostName=hub.azure-devices.net;DeviceId=device""#,
+        );
+        assert!(DuConfig::parse(&valid).is_ok());
+    }
+}
@@ -0,0 +1,446 @@
+use super::{file_watcher, service_config::FeaturesConfig};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use futures_util::{
+    stream::{self, select_all, BoxStream, SelectAll},
+    StreamExt,
+};
+use log::{debug, info, warn};
+use notify::RecommendedWatcher;
+use serde_json::{json, Value};
+use std::path::Path;
+use tokio::{
+    sync::mpsc,
+    time::{interval, Duration},
+};
+
+#[derive(Debug)]
+pub enum Event {
+    Interval,
+    FileChanged,
+}
+
+pub type EventStream = BoxStream<'static, Event>;
+
+/*
+ * Features are the pluggable parts of the twin, e.g. the deviceUpdate component.
+ * Twin dispatches desired properties, direct methods and the events of their streams
+ * to the features which claim them. Disabled features are not even constructed, so that
+ * e.g. a missing device information source cannot prevent the startup.
+ */
+#[async_trait(?Send)]
+pub trait Feature {
+    // as used in the [features] section of the service config and in reported "features"
+    fn name(&self) -> &'static str;
+
+    fn version(&self) -> u8;
+
+    // top level desired properties handled by the feature
+    fn desired_properties(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn direct_methods(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    // desired is null if the property is missing in a complete twin
+    async fn handle_desired(&mut self, _property: &str, _desired: &Value) -> Result<()> {
+        Ok(())
+    }
+
+    async fn handle_direct_method(&mut self, method: &str, _payload: &Value) -> Result<Value> {
+        bail!("unknown direct method \"{method}\"")
+    }
+
+    // called on every authentication
+    async fn report_initial_state(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // called once before the event loop starts
+    fn event_stream(&mut self) -> Result<Option<EventStream>> {
+        Ok(None)
+    }
+
+    async fn handle_event(&mut self, _event: Event) -> Result<()> {
+        Ok(())
+    }
+}
+
+// the enabled features in the order they were registered
+pub struct FeatureRegistry {
+    config: FeaturesConfig,
+    features: Vec<Box<dyn Feature>>,
+    // versions of enabled features, null for disabled ones
+    reported: serde_json::Map<String, Value>,
+}
+
+impl FeatureRegistry {
+    pub fn new(config: FeaturesConfig) -> Self {
+        FeatureRegistry {
+            config,
+            features: vec![],
+            reported: serde_json::Map::new(),
+        }
+    }
+
+    // new is only called if the feature is enabled
+    pub fn register<F: Feature + 'static>(
+        &mut self,
+        name: &'static str,
+        new: impl FnOnce() -> Result<F>,
+    ) -> Result<()> {
+        if !self.config.is_enabled(name) {
+            info!("feature {name} disabled");
+            self.reported.insert(name.to_owned(), Value::Null);
+            return Ok(());
+        }
+
+        let feature = new().with_context(|| format!("feature {name}"))?;
+
+        debug_assert_eq!(feature.name(), name);
+
+        self.reported
+            .insert(name.to_owned(), json!({ "version": feature.version() }));
+        self.features.push(Box::new(feature));
+
+        Ok(())
+    }
+
+    pub fn reported(&self) -> Value {
+        self.reported.clone().into()
+    }
+
+    pub async fn report_initial_state(&mut self) -> Result<()> {
+        for feature in self.features.iter_mut() {
+            feature.report_initial_state().await?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * A complete twin resets the properties which are missing. A failing feature doesn't
+     * keep the others from getting their properties, all errors are returned together.
+     */
+    pub async fn handle_desired(&mut self, desired: &Value, complete: bool) -> Result<()> {
+        let mut errors = vec![];
+
+        for feature in self.features.iter_mut() {
+            for property in feature.desired_properties() {
+                let value = match desired.get(*property) {
+                    Some(value) => value,
+                    None if complete => &Value::Null,
+                    None => continue,
+                };
+
+                if let Err(e) = feature.handle_desired(property, value).await {
+                    warn!("feature {}: desired {property}: {e:#}", feature.name());
+                    errors.push(format!("{}: {property}: {e:#}", feature.name()));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(errors.join(", ")))
+        }
+    }
+
+    pub async fn handle_direct_method(&mut self, method: &str, payload: &Value) -> Result<Value> {
+        let feature = self
+            .features
+            .iter_mut()
+            .find(|feature| feature.direct_methods().contains(&method))
+            .with_context(|| format!("unknown direct method \"{method}\""))?;
+
+        feature.handle_direct_method(method, payload).await
+    }
+
+    // the events of all features, tagged with the index of their feature
+    pub fn event_streams(&mut self) -> Result<SelectAll<BoxStream<'static, (usize, Event)>>> {
+        let mut streams = vec![];
+
+        for (index, feature) in self.features.iter_mut().enumerate() {
+            if let Some(stream) = feature.event_stream()? {
+                streams.push(stream.map(move |event| (index, event)).boxed());
+            }
+        }
+
+        Ok(select_all(streams))
+    }
+
+    pub async fn handle_event(&mut self, index: usize, event: Event) -> Result<()> {
+        let feature = &mut self.features[index];
+        let name = feature.name();
+
+        debug!("event of feature {name}: {event:?}");
+
+        feature
+            .handle_event(event)
+            .await
+            .with_context(|| format!("feature {name}"))
+    }
+}
+
+pub fn interval_stream(period: Duration) -> EventStream {
+    stream::unfold(interval(period), |mut interval| async {
+        interval.tick().await;
+        Some((Event::Interval, interval))
+    })
+    .boxed()
+}
+
+// the returned watcher must be kept alive as long as the stream is used
+pub fn file_stream(files: &[&Path]) -> Result<(RecommendedWatcher, EventStream)> {
+    let (tx_file_changed, rx_file_changed) = mpsc::channel(1);
+    let watcher = file_watcher::watch(files, tx_file_changed)?;
+
+    let stream = stream::unfold(rx_file_changed, |mut rx_file_changed| async {
+        rx_file_changed
+            .recv()
+            .await
+            .map(|()| (Event::FileChanged, rx_file_changed))
+    })
+    .boxed();
+
+    Ok((watcher, stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    type Calls = Rc<RefCell<Vec<String>>>;
+
+    struct Fake {
+        name: &'static str,
+        disabled: bool,
+        desired_properties: &'static [&'static str],
+        direct_methods: &'static [&'static str],
+        events: usize,
+        fail: bool,
+        calls: Calls,
+    }
+
+    impl Fake {
+        fn new(name: &'static str, calls: &Calls) -> Self {
+            Fake {
+                name,
+                disabled: false,
+                desired_properties: &[],
+                direct_methods: &[],
+                events: 0,
+                fail: false,
+                calls: calls.clone(),
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Feature for Fake {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn version(&self) -> u8 {
+            2
+        }
+
+        fn desired_properties(&self) -> &'static [&'static str] {
+            self.desired_properties
+        }
+
+        fn direct_methods(&self) -> &'static [&'static str] {
+            self.direct_methods
+        }
+
+        async fn handle_desired(&mut self, property: &str, desired: &Value) -> Result<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("{}: {property}={desired}", self.name));
+
+            if self.fail {
+                bail!("invalid {property}");
+            }
+
+            Ok(())
+        }
+
+        async fn handle_direct_method(&mut self, method: &str, _payload: &Value) -> Result<Value> {
+            Ok(json!(format!("{}: {method}", self.name)))
+        }
+
+        fn event_stream(&mut self) -> Result<Option<EventStream>> {
+            Ok((self.events > 0)
+                .then(|| stream::iter((0..self.events).map(|_| Event::Interval)).boxed()))
+        }
+
+        async fn handle_event(&mut self, event: Event) -> Result<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("{}: {event:?}", self.name));
+
+            Ok(())
+        }
+    }
+
+    fn registry(features: Vec<Fake>) -> FeatureRegistry {
+        let mut registry = FeatureRegistry::new(FeaturesConfig {
+            disabled: features
+                .iter()
+                .filter(|feature| feature.disabled)
+                .map(|feature| feature.name.to_owned())
+                .collect(),
+        });
+
+        for feature in features {
+            registry.register(feature.name, || Ok(feature)).unwrap();
+        }
+
+        registry
+    }
+
+    #[tokio::test]
+    async fn complete_twin_resets_missing_properties() {
+        let calls = Calls::default();
+        let mut registry = registry(vec![Fake {
+            desired_properties: &["a", "b"],
+            ..Fake::new("fake", &calls)
+        }]);
+
+        registry
+            .handle_desired(&json!({"a": 1}), false)
+            .await
+            .unwrap();
+        assert_eq!(*calls.borrow(), ["fake: a=1"]);
+
+        calls.borrow_mut().clear();
+
+        registry
+            .handle_desired(&json!({"a": 1}), true)
+            .await
+            .unwrap();
+        assert_eq!(*calls.borrow(), ["fake: a=1", "fake: b=null"]);
+    }
+
+    #[tokio::test]
+    async fn failing_feature_does_not_stop_dispatch() {
+        let calls = Calls::default();
+        let mut registry = registry(vec![
+            Fake {
+                desired_properties: &["a"],
+                fail: true,
+                ..Fake::new("first", &calls)
+            },
+            Fake {
+                desired_properties: &["b"],
+                ..Fake::new("second", &calls)
+            },
+        ]);
+
+        let e = registry
+            .handle_desired(&json!({"a": 1, "b": 2}), false)
+            .await
+            .unwrap_err();
+
+        assert_eq!(e.to_string(), "first: a: invalid a");
+        assert_eq!(*calls.borrow(), ["first: a=1", "second: b=2"]);
+    }
+
+    #[tokio::test]
+    async fn disabled_features_are_reported_as_null() {
+        let calls = Calls::default();
+        let mut registry = registry(vec![
+            Fake::new("enabled", &calls),
+            Fake {
+                disabled: true,
+                desired_properties: &["a"],
+                ..Fake::new("disabled", &calls)
+            },
+        ]);
+
+        assert_eq!(
+            registry.reported(),
+            json!({"enabled": {"version": 2}, "disabled": null})
+        );
+
+        // a disabled feature which would fail to construct doesn't fail the startup
+        registry
+            .register("disabled", || -> Result<Fake> { bail!("not available") })
+            .unwrap();
+        assert!(registry
+            .register("failing", || -> Result<Fake> { bail!("not available") })
+            .is_err());
+
+        registry
+            .handle_desired(&json!({"a": 1}), true)
+            .await
+            .unwrap();
+        assert!(calls.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn direct_methods_are_routed_to_their_feature() {
+        let calls = Calls::default();
+        let mut registry = registry(vec![
+            Fake {
+                direct_methods: &["m1"],
+                ..Fake::new("first", &calls)
+            },
+            Fake {
+                direct_methods: &["m2"],
+                ..Fake::new("second", &calls)
+            },
+        ]);
+
+        assert_eq!(
+            registry
+                .handle_direct_method("m2", &Value::Null)
+                .await
+                .unwrap(),
+            "second: m2"
+        );
+        assert!(registry
+            .handle_direct_method("m3", &Value::Null)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn events_are_tagged_with_their_feature() {
+        let calls = Calls::default();
+        let mut registry = registry(vec![
+            Fake {
+                events: 1,
+                ..Fake::new("first", &calls)
+            },
+            Fake::new("quiet", &calls),
+            Fake {
+                events: 2,
+                ..Fake::new("third", &calls)
+            },
+        ]);
+
+        let mut events = registry.event_streams().unwrap();
+        let mut indexes = vec![];
+
+        while let Some((index, event)) = events.next().await {
+            indexes.push(index);
+            registry.handle_event(index, event).await.unwrap();
+        }
+
+        indexes.sort();
+        assert_eq!(indexes, [0, 2, 2]);
+
+        let mut calls = calls.borrow().clone();
+        calls.sort();
+        assert_eq!(
+            calls,
+            ["first: Interval", "third: Interval", "third: Interval"]
+        );
+    }
+}
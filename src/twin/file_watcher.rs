@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{ffi::OsString, path::Path};
+use tokio::sync::mpsc;
+
+/*
+ * Provisioning tools usually replace files by renaming a temporary file, which invalidates
+ * a watch on the file itself. That's why we watch the parent directories and filter by name.
+ * The returned watcher must be kept alive as long as changes should be notified.
+ */
+pub fn watch(files: &[&Path], tx_file_changed: mpsc::Sender<()>) -> Result<RecommendedWatcher> {
+    let file_names: Vec<OsString> = files
+        .iter()
+        .map(|file| {
+            file.file_name()
+                .map(|name| name.to_owned())
+                .with_context(|| format!("watch: invalid file \"{}\"", file.display()))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("watch: {e:#}");
+                return;
+            }
+        };
+
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+
+        if event.paths.iter().any(|path| {
+            path.file_name()
+                .is_some_and(|name| file_names.iter().any(|file_name| file_name == name))
+        }) {
+            debug!("watch: {event:?}");
+            // a reload is already pending if the channel is full
+            let _ = tx_file_changed.try_send(());
+        }
+    })
+    .context("watch: cannot create watcher")?;
+
+    for file in files {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("watch: cannot watch \"{}\"", dir.display()))?;
+    }
+
+    Ok(watcher)
+}
@@ -0,0 +1,197 @@
+use super::{consent, reported, service_config, workflow};
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{fs, path::Path, time::Instant};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+const HEALTH_FILE: &str = "health.json";
+
+// json state files whose integrity is reported
+const STATE_FILES: [&str; 5] = [
+    workflow::WORKFLOW_FILE,
+    workflow::FAILED_WORKFLOW_FILE,
+    reported::REPORTED_FILE,
+    consent::CONSENT_FILE,
+    HEALTH_FILE,
+];
+
+// statistics which survive restarts and reboots
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+struct Statistics {
+    deployments_processed: u64,
+}
+
+/*
+ * Reported as "service_health" in order to spot stuck agents, e.g.:
+ * {
+ *     "uptime_secs": 3600,
+ *     "last_twin_sync": "2023-10-01T12:00:00Z",
+ *     "watchdog_interval_ms": 30000,
+ *     "deployments_processed": 3,
+ *     "last_error": {"time": "2023-10-01T11:00:00Z", "message": "..."},
+ *     "state_files": {"workflow.json": "ok", "reported.json": "corrupt: ...", ...}
+ * }
+ */
+pub struct ServiceHealth {
+    started_at: Instant,
+    watchdog_interval_ms: Option<u64>,
+    last_twin_sync: Option<OffsetDateTime>,
+    last_error: Option<(OffsetDateTime, String)>,
+}
+
+impl ServiceHealth {
+    pub fn new(watchdog_interval_micros: Option<u64>) -> Self {
+        ServiceHealth {
+            started_at: Instant::now(),
+            watchdog_interval_ms: watchdog_interval_micros.map(|micros| micros / 1000),
+            last_twin_sync: None,
+            last_error: None,
+        }
+    }
+
+    // desired properties were received or reported properties were sent
+    pub fn twin_synced(&mut self) {
+        self.last_twin_sync = Some(OffsetDateTime::now_utc());
+    }
+
+    pub fn error(&mut self, message: String) {
+        self.last_error = Some((OffsetDateTime::now_utc(), message));
+    }
+
+    pub async fn report(&self) -> Result<serde_json::Value> {
+        let last_error = match &self.last_error {
+            Some((time, message)) => json!({"time": time.format(&Rfc3339)?, "message": message}),
+            None => serde_json::Value::Null,
+        };
+
+        let last_twin_sync = self
+            .last_twin_sync
+            .map(|time| time.format(&Rfc3339))
+            .transpose()?;
+        // reading the state files must not block the event loop
+        let (statistics, state_files) = tokio::task::spawn_blocking(|| {
+            (
+                load_statistics(&state_dir().join(HEALTH_FILE)),
+                state_files(state_dir()),
+            )
+        })
+        .await
+        .context("report: read state files")?;
+
+        Ok(json!({
+            "service_health": {
+                "uptime_secs": self.started_at.elapsed().as_secs(),
+                "last_twin_sync": last_twin_sync,
+                "watchdog_interval_ms": self.watchdog_interval_ms,
+                "deployments_processed": statistics.deployments_processed,
+                "last_error": last_error,
+                "state_files": state_files
+            }
+        }))
+    }
+}
+
+// to be called once per deployment with its final result
+pub fn count_deployment() {
+    let path = state_dir().join(HEALTH_FILE);
+    let mut statistics = load_statistics(&path);
+
+    statistics.deployments_processed += 1;
+
+    if let Err(e) = save_statistics(&path, &statistics) {
+        warn!("count deployment: {e:#}");
+    }
+}
+
+fn load_statistics(path: &Path) -> Statistics {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| {
+            serde_json::from_str(&content)
+                .map_err(|e| warn!("ignore invalid {}: {e}", path.display()))
+                .ok()
+        })
+        .unwrap_or_default()
+}
+
+fn save_statistics(path: &Path, statistics: &Statistics) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("save_statistics: cannot create state dir")?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+
+    fs::write(&tmp_path, serde_json::to_vec(statistics)?)
+        .context("save_statistics: cannot write")?;
+    fs::rename(&tmp_path, path).context("save_statistics: cannot rename")
+}
+
+/*
+ * "ok" or why a file cannot be parsed. Missing files are normal, e.g. there is no
+ * workflow.json without a deployment in progress, so only existing files are listed.
+ */
+fn state_files(state_dir: &Path) -> serde_json::Value {
+    STATE_FILES
+        .iter()
+        .filter_map(|file| {
+            let status = match fs::read_to_string(state_dir.join(file)) {
+                Ok(content) => match serde_json::from_str::<serde_json::Value>(&content) {
+                    Ok(_) => "ok".to_owned(),
+                    Err(e) => format!("corrupt: {e}"),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+                Err(e) => format!("unreadable: {e}"),
+            };
+
+            Some((file.to_string(), json!(status)))
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn state_dir() -> &'static Path {
+    &service_config::paths().state_dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_file_integrity() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        fs::write(tmp_dir.path().join(workflow::WORKFLOW_FILE), "{}").unwrap();
+        fs::write(tmp_dir.path().join(reported::REPORTED_FILE), "{").unwrap();
+
+        let state_files = state_files(tmp_dir.path());
+
+        assert_eq!(state_files[workflow::WORKFLOW_FILE], "ok");
+        assert!(state_files[reported::REPORTED_FILE]
+            .as_str()
+            .unwrap()
+            .starts_with("corrupt: "));
+        assert!(state_files.get(consent::CONSENT_FILE).is_none());
+    }
+
+    #[test]
+    fn statistics_survive_restart() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join(HEALTH_FILE);
+
+        assert_eq!(load_statistics(&path), Statistics::default());
+
+        save_statistics(
+            &path,
+            &Statistics {
+                deployments_processed: 2,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(load_statistics(&path).deployments_processed, 2);
+    }
+}
@@ -0,0 +1,37 @@
+use super::workflow::StepResult;
+use crate::systemd;
+use log::{error, info};
+
+// name of the health check entry in lastInstallResult.stepResults
+pub const STEP_NAME: &str = "healthCheck";
+
+const START_UNIT_TIMEOUT_SECS: u64 = 60;
+
+// starts all configured units one after another and waits for their jobs to be done
+pub async fn run(units: &[String]) -> Option<StepResult> {
+    if units.is_empty() {
+        return None;
+    }
+
+    let mut details = vec![];
+    let mut success = true;
+
+    for unit in units {
+        info!("start health check unit \"{unit}\"");
+
+        match systemd::start_unit(START_UNIT_TIMEOUT_SECS, unit).await {
+            Ok(()) => details.push(format!("{unit}: done")),
+            Err(e) => {
+                error!("health check unit \"{unit}\": {e:#}");
+                details.push(format!("{unit}: {e:#}"));
+                success = false;
+            }
+        }
+    }
+
+    if success {
+        Some(StepResult::success(details.join(", ")))
+    } else {
+        Some(StepResult::failure(details.join(", ")))
+    }
+}
@@ -0,0 +1,384 @@
+use anyhow::{bail, ensure, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset, Weekday};
+use time_tz::{
+    timezones, Offset, OffsetDateTimeExt, OffsetResult, PrimitiveDateTimeExt, TimeZone, Tz,
+};
+
+/*
+ * maintenance windows are configured via twin desired properties or the service config, e.g.:
+ * "maintenance_windows": {
+ *     "phases": ["install", "reboot"],
+ *     "time_zone": "Europe/Berlin",
+ *     "windows": [
+ *         { "weekdays": ["sat", "sun"], "start": "22:00", "end": "04:00" }
+ *     ]
+ * }
+ * windows without weekdays apply to every day, windows with end <= start span midnight.
+ * time_zone is an IANA time zone, which follows daylight saving time. Alternatively a fixed
+ * utc_offset, e.g. "+02:00", can be configured. Both default to UTC.
+ */
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenancePhase {
+    Install,
+    Reboot,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+struct WindowConfig {
+    #[serde(default)]
+    weekdays: Vec<String>,
+    start: String,
+    end: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct MaintenanceWindowsConfig {
+    #[serde(default)]
+    phases: Vec<MaintenancePhase>,
+    #[serde(default)]
+    time_zone: Option<String>,
+    #[serde(default)]
+    utc_offset: Option<String>,
+    #[serde(default)]
+    windows: Vec<WindowConfig>,
+}
+
+#[derive(Clone, Debug)]
+struct Window {
+    weekdays: Vec<Weekday>,
+    start: Time,
+    end: Time,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Zone {
+    Offset(UtcOffset),
+    Tz(&'static Tz),
+}
+
+#[derive(Clone, Debug)]
+pub struct MaintenanceWindows {
+    config: MaintenanceWindowsConfig,
+    zone: Zone,
+    windows: Vec<Window>,
+}
+
+impl Default for MaintenanceWindows {
+    fn default() -> Self {
+        MaintenanceWindows {
+            config: MaintenanceWindowsConfig::default(),
+            zone: Zone::Offset(UtcOffset::UTC),
+            windows: vec![],
+        }
+    }
+}
+
+impl MaintenanceWindows {
+    pub fn from_desired(desired: &serde_json::Value) -> Result<Self> {
+        if desired.is_null() {
+            info!("maintenance windows: none configured");
+            return Ok(MaintenanceWindows::default());
+        }
+
+        let config: MaintenanceWindowsConfig = serde_json::from_value(desired.clone())
+            .context("maintenance windows: cannot parse desired")?;
+
+        Self::from_config(config)
+    }
+
+    pub fn from_config(config: MaintenanceWindowsConfig) -> Result<Self> {
+        let zone = match (&config.time_zone, &config.utc_offset) {
+            (Some(_), Some(_)) => {
+                bail!("maintenance windows: either time_zone or utc_offset expected")
+            }
+            (Some(time_zone), None) => {
+                Zone::Tz(timezones::get_by_name(time_zone).with_context(|| {
+                    format!("maintenance windows: unknown time_zone \"{time_zone}\"")
+                })?)
+            }
+            (None, Some(offset)) => Zone::Offset(parse_offset(offset)?),
+            (None, None) => Zone::Offset(UtcOffset::UTC),
+        };
+
+        let windows = config
+            .windows
+            .iter()
+            .map(|w| {
+                Ok(Window {
+                    weekdays: w
+                        .weekdays
+                        .iter()
+                        .map(|d| parse_weekday(d))
+                        .collect::<Result<_>>()?,
+                    start: parse_time(&w.start)?,
+                    end: parse_time(&w.end)?,
+                })
+            })
+            .collect::<Result<Vec<Window>>>()?;
+
+        ensure!(
+            config.phases.is_empty() || !windows.is_empty(),
+            "maintenance windows: phases configured without any window"
+        );
+
+        info!("maintenance windows: {config:?}");
+
+        Ok(MaintenanceWindows {
+            config,
+            zone,
+            windows,
+        })
+    }
+
+    pub fn config(&self) -> &MaintenanceWindowsConfig {
+        &self.config
+    }
+
+    pub fn applies_to(&self, phase: MaintenancePhase) -> bool {
+        self.config.phases.contains(&phase)
+    }
+
+    // returns `now` if a window is currently open
+    pub fn next_window_start(&self, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        let now = self.zone.local(now);
+        let mut next: Option<OffsetDateTime> = None;
+
+        // start one day back in order to catch windows spanning midnight
+        for day in -1..=7 {
+            let date = now.date() + Duration::days(day);
+
+            for window in &self.windows {
+                if !window.weekdays.is_empty() && !window.weekdays.contains(&date.weekday()) {
+                    continue;
+                }
+
+                let (start, end) = window.bounds(date, self.zone);
+
+                if start <= now && now < end {
+                    return Some(now);
+                }
+
+                if now < start {
+                    next = Some(next.map_or(start, |next| next.min(start)));
+                }
+            }
+        }
+
+        next
+    }
+}
+
+impl Zone {
+    fn local(&self, datetime: OffsetDateTime) -> OffsetDateTime {
+        match self {
+            Zone::Offset(offset) => datetime.to_offset(*offset),
+            Zone::Tz(tz) => datetime.to_timezone(*tz),
+        }
+    }
+
+    fn assume(&self, datetime: PrimitiveDateTime) -> OffsetDateTime {
+        match self {
+            Zone::Offset(offset) => datetime.assume_offset(*offset),
+            Zone::Tz(tz) => match datetime.assume_timezone(*tz) {
+                // the earlier one if the time repeats when daylight saving time ends
+                OffsetResult::Some(datetime) | OffsetResult::Ambiguous(datetime, _) => datetime,
+                // skipped when daylight saving time starts, i.e. 02:30 is 03:30
+                OffsetResult::None => datetime.assume_offset(
+                    tz.get_offset_utc(&(datetime - Duration::days(1)).assume_utc())
+                        .to_utc(),
+                ),
+            },
+        }
+    }
+}
+
+impl Window {
+    fn bounds(&self, date: Date, zone: Zone) -> (OffsetDateTime, OffsetDateTime) {
+        let start = zone.assume(date.with_time(self.start));
+        let end_date = if self.end <= self.start {
+            date + Duration::days(1)
+        } else {
+            date
+        };
+
+        (start, zone.assume(end_date.with_time(self.end)))
+    }
+}
+
+fn parse_time(time: &str) -> Result<Time> {
+    let (hour, minute) = time
+        .split_once(':')
+        .with_context(|| format!("maintenance windows: invalid time \"{time}\""))?;
+
+    Time::from_hms(hour.trim().parse()?, minute.trim().parse()?, 0)
+        .with_context(|| format!("maintenance windows: invalid time \"{time}\""))
+}
+
+fn parse_offset(offset: &str) -> Result<UtcOffset> {
+    let (sign, offset_abs) = if let Some(offset_abs) = offset.strip_prefix('+') {
+        (1, offset_abs)
+    } else if let Some(offset_abs) = offset.strip_prefix('-') {
+        (-1, offset_abs)
+    } else {
+        bail!("maintenance windows: invalid utc_offset \"{offset}\"")
+    };
+
+    let time = parse_time(offset_abs)?;
+
+    UtcOffset::from_hms(sign * time.hour() as i8, sign * time.minute() as i8, 0)
+        .with_context(|| format!("maintenance windows: invalid utc_offset \"{offset}\""))
+}
+
+fn parse_weekday(weekday: &str) -> Result<Weekday> {
+    let weekday = match weekday.to_lowercase().as_str() {
+        "mon" | "monday" => Weekday::Monday,
+        "tue" | "tuesday" => Weekday::Tuesday,
+        "wed" | "wednesday" => Weekday::Wednesday,
+        "thu" | "thursday" => Weekday::Thursday,
+        "fri" | "friday" => Weekday::Friday,
+        "sat" | "saturday" => Weekday::Saturday,
+        "sun" | "sunday" => Weekday::Sunday,
+        _ => bail!("maintenance windows: invalid weekday \"{weekday}\""),
+    };
+
+    Ok(weekday)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use time::Month;
+
+    // october 2023 starts on a sunday, e.g. the 2nd is a monday
+    fn at(day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        Date::from_calendar_date(2023, Month::October, day)
+            .unwrap()
+            .with_hms(hour, minute, 0)
+            .unwrap()
+            .assume_utc()
+    }
+
+    fn windows(desired: serde_json::Value) -> MaintenanceWindows {
+        MaintenanceWindows::from_desired(&desired).unwrap()
+    }
+
+    #[test]
+    fn window_crossing_midnight() {
+        let windows = windows(json!({
+            "phases": ["reboot"],
+            "windows": [{ "start": "22:00", "end": "04:00" }]
+        }));
+
+        assert_eq!(windows.next_window_start(at(3, 2, 0)), Some(at(3, 2, 0)));
+        assert_eq!(windows.next_window_start(at(3, 4, 0)), Some(at(3, 22, 0)));
+        assert_eq!(
+            windows.next_window_start(at(3, 23, 59)),
+            Some(at(3, 23, 59))
+        );
+    }
+
+    #[test]
+    fn weekday_wrap_around() {
+        let windows = windows(json!({
+            "phases": ["install"],
+            "windows": [{ "weekdays": ["sat", "Sunday"], "start": "22:00", "end": "02:00" }]
+        }));
+
+        // monday waits for saturday
+        assert_eq!(windows.next_window_start(at(2, 10, 0)), Some(at(7, 22, 0)));
+        // the sunday window reaches into monday
+        assert_eq!(windows.next_window_start(at(9, 1, 0)), Some(at(9, 1, 0)));
+        assert_eq!(windows.next_window_start(at(9, 2, 0)), Some(at(14, 22, 0)));
+
+        let windows = self::windows(json!({
+            "phases": ["install"],
+            "windows": [{ "weekdays": ["sun"], "start": "10:00", "end": "12:00" }]
+        }));
+
+        // the same weekday a week later
+        assert_eq!(windows.next_window_start(at(8, 13, 0)), Some(at(15, 10, 0)));
+    }
+
+    #[test]
+    fn now_inside_window_with_offset() {
+        let windows = windows(json!({
+            "phases": ["install", "reboot"],
+            "utc_offset": "+02:00",
+            "windows": [{ "start": "22:00", "end": "23:00" }]
+        }));
+
+        assert!(windows.applies_to(MaintenancePhase::Reboot));
+        assert_eq!(
+            windows.next_window_start(at(3, 20, 30)),
+            Some(at(3, 20, 30))
+        );
+        assert_eq!(windows.next_window_start(at(3, 21, 0)), Some(at(4, 20, 0)));
+    }
+
+    #[test]
+    fn time_zone_follows_daylight_saving_time() {
+        let windows = windows(json!({
+            "phases": ["install"],
+            "time_zone": "Europe/Berlin",
+            "windows": [{ "start": "02:30", "end": "04:00" }]
+        }));
+
+        // CEST until sunday, 29th 03:00, when 02:00-03:00 repeats
+        assert_eq!(
+            windows.next_window_start(at(27, 12, 0)),
+            Some(at(28, 0, 30))
+        );
+        assert_eq!(
+            windows.next_window_start(at(28, 12, 0)),
+            Some(at(29, 0, 30))
+        );
+        assert_eq!(
+            windows.next_window_start(at(29, 2, 30)),
+            Some(at(29, 2, 30))
+        );
+        assert_eq!(windows.next_window_start(at(29, 3, 0)), Some(at(30, 1, 30)));
+
+        // 02:00-03:00 is skipped on march 26th 2023, so the window starts at 03:30 CEST
+        let march_26 = Date::from_calendar_date(2023, Month::March, 26).unwrap();
+        assert_eq!(
+            windows.next_window_start(march_26.with_hms(0, 0, 0).unwrap().assume_utc()),
+            Some(march_26.with_hms(1, 30, 0).unwrap().assume_utc())
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        for utc_offset in ["02:00", "+2", "+25:00", "-01:60", ""] {
+            assert!(MaintenanceWindows::from_desired(&json!({
+                "utc_offset": utc_offset,
+                "windows": [{ "start": "22:00", "end": "04:00" }]
+            }))
+            .is_err());
+        }
+
+        assert!(MaintenanceWindows::from_desired(&json!({
+            "windows": [{ "weekdays": ["someday"], "start": "22:00", "end": "04:00" }]
+        }))
+        .is_err());
+        assert!(MaintenanceWindows::from_desired(&json!({ "phases": ["reboot"] })).is_err());
+        assert!(MaintenanceWindows::from_desired(&json!({
+            "time_zone": "Mars/Olympus_Mons",
+            "windows": [{ "start": "22:00", "end": "04:00" }]
+        }))
+        .is_err());
+        assert!(MaintenanceWindows::from_desired(&json!({
+            "time_zone": "Europe/Berlin",
+            "utc_offset": "+01:00",
+            "windows": [{ "start": "22:00", "end": "04:00" }]
+        }))
+        .is_err());
+        assert!(windows(serde_json::Value::Null)
+            .next_window_start(at(2, 0, 0))
+            .is_none());
+    }
+}
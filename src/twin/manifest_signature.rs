@@ -0,0 +1,190 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use ring::{
+    digest::{digest, SHA256},
+    signature::{RsaPublicKeyComponents, RSA_PKCS1_2048_8192_SHA256},
+};
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+/*
+ * updateManifestSignature is a compact JWS as created by device update:
+ * header:  {"alg": "RS256", "sjwk": "<JWS of the signing key>"}
+ * payload: {"sha256": "<base64 encoded sha256 of updateManifest>"}
+ * The signing key is a JWK, which itself is signed by one of our root keys, e.g.
+ * {"alg": "RS256", "kid": "<root key id>"}. Without a trusted root key there is no update.
+ */
+#[derive(Debug, Deserialize)]
+pub struct RootKeys {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: String,
+    e: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+    sjwk: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Payload {
+    sha256: String,
+}
+
+struct Jws<'a> {
+    header: Header,
+    signing_input: &'a str,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl RootKeys {
+    // JWK set, e.g. {"keys": [{"kty": "RSA", "kid": "...", "n": "...", "e": "AQAB"}]}
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        serde_json::from_str(
+            &fs::read_to_string(path)
+                .with_context(|| format!("load: cannot read {}", path.display()))?,
+        )
+        .with_context(|| format!("load: invalid {}", path.display()))
+    }
+}
+
+pub fn verify(manifest: &str, signature: &str, root_keys: &RootKeys) -> Result<()> {
+    let jws = Jws::parse(signature).context("verify: invalid manifest signature")?;
+    let sjwk = jws
+        .header
+        .sjwk
+        .as_deref()
+        .context("verify: signing key missing")?;
+    let sjwk = Jws::parse(sjwk).context("verify: invalid signing key")?;
+    let kid = sjwk
+        .header
+        .kid
+        .as_deref()
+        .context("verify: root key id of signing key missing")?;
+    let root_key = root_keys
+        .keys
+        .iter()
+        .find(|key| key.kid.as_deref() == Some(kid))
+        .with_context(|| format!("verify: unknown root key \"{kid}\""))?;
+
+    let signing_key: Jwk = serde_json::from_slice(
+        sjwk.verify(root_key)
+            .context("verify: signing key not signed by root key")?,
+    )
+    .context("verify: invalid signing key")?;
+
+    let payload: Payload = serde_json::from_slice(
+        jws.verify(&signing_key)
+            .context("verify: manifest not signed by signing key")?,
+    )
+    .context("verify: invalid manifest signature payload")?;
+
+    ensure!(
+        digest(&SHA256, manifest.as_bytes()).as_ref()
+            == base64::decode(payload.sha256).context("verify: invalid manifest hash")?,
+        "verify: manifest hash mismatch"
+    );
+
+    Ok(())
+}
+
+impl<'a> Jws<'a> {
+    fn parse(jws: &'a str) -> Result<Self> {
+        let (signing_input, signature) =
+            jws.rsplit_once('.').context("parse: signature missing")?;
+        let (header, payload) = signing_input
+            .split_once('.')
+            .context("parse: payload missing")?;
+
+        Ok(Jws {
+            header: serde_json::from_slice(&decode(header)?).context("parse: invalid header")?,
+            signing_input,
+            payload: decode(payload)?,
+            signature: decode(signature)?,
+        })
+    }
+
+    // returns the payload if it is signed by key
+    fn verify(&self, key: &Jwk) -> Result<&[u8]> {
+        ensure!(
+            self.header.alg == "RS256",
+            "verify: unsupported algorithm \"{}\"",
+            self.header.alg
+        );
+        ensure!(
+            key.kty == "RSA",
+            "verify: unsupported key type \"{}\"",
+            key.kty
+        );
+
+        RsaPublicKeyComponents {
+            n: decode(&key.n)?,
+            e: decode(&key.e)?,
+        }
+        .verify(
+            &RSA_PKCS1_2048_8192_SHA256,
+            self.signing_input.as_bytes(),
+            &self.signature,
+        )
+        .map_err(|_| anyhow!("verify: invalid signature"))?;
+
+        Ok(&self.payload)
+    }
+}
+
+fn decode(base64url: &str) -> Result<Vec<u8>> {
+    base64::decode_config(base64url, base64::URL_SAFE_NO_PAD).context("decode: invalid base64url")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNATURE_DIR: &str = "testfiles/signature";
+
+    fn read(file: &str) -> String {
+        fs::read_to_string(Path::new(SIGNATURE_DIR).join(file)).unwrap()
+    }
+
+    #[test]
+    fn verify_signed_manifest() {
+        let root_keys = RootKeys::load(Path::new(SIGNATURE_DIR).join("root-keys.json")).unwrap();
+        let manifest = read("update-manifest.json");
+        let signature = read("update-manifest.jws");
+
+        verify(&manifest, &signature, &root_keys).unwrap();
+
+        // manifest was changed after signing
+        let tampered = manifest.replace("image.swu", "other.swu");
+        assert!(verify(&tampered, &signature, &root_keys)
+            .unwrap_err()
+            .to_string()
+            .contains("hash mismatch"));
+
+        // signing key is not signed by our root key
+        assert!(verify(
+            &manifest,
+            &read("update-manifest-untrusted.jws"),
+            &root_keys
+        )
+        .unwrap_err()
+        .to_string()
+        .contains("not signed by root key"));
+
+        assert!(verify(&manifest, &signature, &RootKeys { keys: vec![] })
+            .unwrap_err()
+            .to_string()
+            .contains("unknown root key"));
+        assert!(verify(&manifest, "invalid", &root_keys).is_err());
+    }
+}
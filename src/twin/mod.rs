@@ -1,40 +1,147 @@
-use crate::systemd::WatchdogManager;
-mod adu;
-use crate::twin::adu::Adu;
-use anyhow::Result;
+use crate::systemd::{self, WatchdogManager};
+pub mod adu;
+pub mod boot_validation;
+pub mod consent;
+pub mod deployment;
+pub mod device_info;
+pub mod device_information;
+pub mod diagnostics;
+pub mod du_config;
+pub mod feature;
+pub mod file_watcher;
+pub mod health;
+pub mod health_check;
+pub mod maintenance_window;
+pub mod manifest_signature;
+pub mod os_release;
+pub mod reported;
+pub mod service_config;
+pub mod sw_versions;
+pub mod telemetry;
+pub mod workflow;
+use crate::twin::{
+    adu::Adu,
+    consent::{Consent, GeneralConsent},
+    device_information::DeviceInformation,
+    diagnostics::Diagnostics,
+    du_config::{AgentConfig, ClientKind, ConnectionSource, ConnectionType, DuConfig},
+    feature::{Event, FeatureRegistry},
+    health::ServiceHealth,
+    reported::ReportedCache,
+    service_config::{DownloadConfig, ServiceConfig},
+    telemetry::Telemetry,
+    workflow::{Reboot, Workflow},
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use azure_iot_sdk::client::*;
 use futures_util::{FutureExt, StreamExt};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde_json::json;
 use signal_hook::consts::TERM_SIGNALS;
 use signal_hook_tokio::Signals;
-use std::future::{pending, Future};
+use std::{
+    collections::VecDeque,
+    future::{pending, Future},
+};
 use tokio::{
     select,
-    sync::mpsc,
-    time::{interval, Duration, Interval},
+    sync::{mpsc, watch},
+    time::{interval, sleep_until, Duration, Instant, Interval},
 };
 
+const MAX_PENDING_D2C_MESSAGES: usize = 100;
+const SERVICE_HEALTH_INTERVAL_SECS: u64 = 300;
+// reports while disconnected are persisted at most once in this interval
+const PERSIST_REPORTED_DELAY_SECS: u64 = 60;
+
 pub struct Twin {
-    iothub_client: Box<dyn IotHub>,
-    authenticated_once: bool,
+    // None until the first client got created
+    iothub_client: Option<Box<dyn IotHub>>,
+    authenticated: bool,
+    reconnect_attempt: u32,
+    reconnect_at: Option<Instant>,
+    reported: ReportedCache,
+    persist_reported_at: Option<Instant>,
     tx_reported_properties: mpsc::Sender<serde_json::Value>,
     rx_reported_properties: mpsc::Receiver<serde_json::Value>,
-    adu: Adu,
+    rx_request_reboot: mpsc::Receiver<Reboot>,
+    rx_d2c_messages: mpsc::Receiver<IotMessage>,
+    // messages are queued while we are not authenticated
+    pending_d2c_messages: VecDeque<IotMessage>,
+    features: FeatureRegistry,
+    health: ServiceHealth,
+    service_config: ServiceConfig,
+    service_config_overrides: serde_json::Value,
+    tx_download_config: watch::Sender<DownloadConfig>,
 }
 
 impl Twin {
-    pub fn new(client: Box<dyn IotHub>) -> Result<Self> {
+    pub fn new(
+        du_config: &DuConfig,
+        agent_config: &AgentConfig,
+        watchdog_interval_micros: Option<u64>,
+    ) -> Result<Self> {
         let (tx_reported_properties, rx_reported_properties) = mpsc::channel(100);
+        let (tx_request_reboot, rx_request_reboot) = mpsc::channel(1);
+        let (tx_d2c_messages, rx_d2c_messages) = mpsc::channel(100);
+        // without a valid stored consent every consent step fails
+        let (tx_general_consent, rx_general_consent) =
+            watch::channel(GeneralConsent::load().unwrap_or_else(|e| {
+                warn!("ignore general consent: {e:#}");
+                GeneralConsent::default()
+            }));
+
+        // the effective download config, i.e. including the desired overrides
+        let (tx_download_config, rx_download_config) =
+            watch::channel(service_config::get().download.clone());
 
-        let adu = Adu::new(tx_reported_properties.clone())?;
+        let mut features = FeatureRegistry::new(service_config::get().features.clone());
+
+        features.register("device_update", || {
+            Adu::new(
+                du_config,
+                agent_config,
+                tx_reported_properties.clone(),
+                tx_request_reboot,
+                Telemetry::new(tx_d2c_messages),
+                rx_general_consent,
+                rx_download_config.clone(),
+            )
+        })?;
+        features.register("device_information", || {
+            DeviceInformation::new(du_config, tx_reported_properties.clone())
+        })?;
+        features.register("device_update_consent", || {
+            Ok(Consent::new(
+                tx_reported_properties.clone(),
+                tx_general_consent,
+            ))
+        })?;
+        features.register("diagnostics", || {
+            Ok(Diagnostics::new(
+                tx_reported_properties.clone(),
+                rx_download_config,
+            ))
+        })?;
 
         Ok(Twin {
-            iothub_client: client,
+            iothub_client: None,
             tx_reported_properties: tx_reported_properties.clone(),
             rx_reported_properties,
-            authenticated_once: false,
-            adu,
+            rx_request_reboot,
+            rx_d2c_messages,
+            pending_d2c_messages: VecDeque::new(),
+            authenticated: false,
+            reconnect_attempt: 0,
+            // the client gets created in the event loop, so that we can retry meanwhile
+            reconnect_at: Some(Instant::now()),
+            reported: ReportedCache::load(),
+            persist_reported_at: None,
+            features,
+            health: ServiceHealth::new(watchdog_interval_micros),
+            service_config: service_config::get().clone(),
+            service_config_overrides: serde_json::Value::Null,
+            tx_download_config,
         })
     }
 
@@ -43,11 +150,23 @@ impl Twin {
         self.tx_reported_properties
             .send(json!({
                 "module-version": env!("CARGO_PKG_VERSION"),
-                "azure-sdk-version": IotHubClient::sdk_version_string()
+                "azure-sdk-version": IotHubClient::sdk_version_string(),
+                "features": self.features.reported()
             }))
             .await?;
 
-        self.adu.report_initial_state().await
+        self.features.report_initial_state().await?;
+
+        self.report_health().await
+    }
+
+    async fn report_health(&mut self) -> Result<()> {
+        let health = self.health.report().await?;
+
+        self.tx_reported_properties
+            .send(health)
+            .await
+            .context("report_health: report_impl")
     }
 
     async fn handle_connection_status(&mut self, auth_status: AuthenticationStatus) -> Result<()> {
@@ -55,67 +174,285 @@ impl Twin {
 
         match auth_status {
             AuthenticationStatus::Authenticated => {
-                self.authenticated_once = true;
+                self.authenticated = true;
+                self.reconnect_attempt = 0;
+                self.reconnect_at = None;
+                // send what was reported while we were disconnected
+                self.flush_reported();
+                self.flush_d2c_messages();
                 self.init().await?;
             }
             AuthenticationStatus::Unauthenticated(reason) => {
-                anyhow::ensure!(
-                    matches!(reason, UnauthenticatedReason::ExpiredSasToken),
-                    "No connection. Reason: {reason:?}"
-                );
+                self.authenticated = false;
+
+                match reason {
+                    // the client reconnects on its own, deployments keep running meanwhile
+                    UnauthenticatedReason::ExpiredSasToken
+                    | UnauthenticatedReason::NoNetwork
+                    | UnauthenticatedReason::CommunicationError
+                    | UnauthenticatedReason::NoPingResponse => {
+                        warn!("connection lost ({reason:?}), wait for reconnect")
+                    }
+                    // the client gave up, so we have to create a new one
+                    UnauthenticatedReason::RetryExpired => {
+                        self.schedule_reconnect(format!("{reason:?}"))?
+                    }
+                    _ => bail!("No connection. Reason: {reason:?}"),
+                }
             }
         }
 
         Ok(())
     }
 
+    fn schedule_reconnect(&mut self, reason: String) -> Result<()> {
+        let retry = &self.service_config.retry;
+
+        // giving up would kill a deployment in progress, e.g. waiting for its reboot window
+        ensure!(
+            !retry.exhausted(self.reconnect_attempt) || !matches!(Workflow::load(), Ok(None)),
+            "No connection. Reason: {reason}, giving up after {} attempts",
+            self.reconnect_attempt
+        );
+
+        let delay = retry.delay(self.reconnect_attempt);
+
+        warn!("no connection ({reason}), connect in {delay:?}");
+
+        self.reconnect_attempt += 1;
+        self.reconnect_at = Some(Instant::now() + delay);
+
+        Ok(())
+    }
+
+    async fn connect(
+        &mut self,
+        builder: &IotHubClientBuilder,
+        agent_config: &AgentConfig,
+    ) -> Result<()> {
+        self.reconnect_at = None;
+
+        if let Some(mut client) = self.iothub_client.take() {
+            client.shutdown().await;
+        }
+
+        info!("connect (attempt {})", self.reconnect_attempt);
+
+        match build_client(builder, agent_config).await {
+            Ok(client) => {
+                self.iothub_client = Some(client);
+                // reports handed over to a former client might got lost
+                self.reported.invalidate();
+                Ok(())
+            }
+            // the identity service might not be available yet, e.g. while booting
+            Err(e) if agent_config.connection_source.connection_type == ConnectionType::Ais => {
+                self.schedule_reconnect(format!("{e:#}"))
+            }
+            Err(e) => Err(e).context("connect: cannot create client"),
+        }
+    }
+
+    // hands over pending reported properties and shuts the client down, may be called twice
+    async fn shutdown(&mut self) {
+        while let Ok(reported) = self.rx_reported_properties.try_recv() {
+            self.reported.push(reported);
+        }
+
+        self.flush_reported();
+
+        if let Err(e) = self.reported.persist() {
+            warn!("shutdown: {e:#}");
+        }
+
+        if let Some(mut client) = self.iothub_client.take() {
+            client.shutdown().await;
+        }
+    }
+
+    fn persist_reported(&mut self) {
+        self.persist_reported_at = None;
+
+        if let Err(e) = self.reported.persist() {
+            warn!("{e:#}");
+            self.health.error(format!("{e:#}"));
+        }
+    }
+
+    // reports are coalesced and only sent while we are authenticated
+    fn report(&mut self, reported: serde_json::Value) {
+        self.reported.push(reported);
+
+        while let Ok(reported) = self.rx_reported_properties.try_recv() {
+            self.reported.push(reported);
+        }
+
+        self.flush_reported();
+    }
+
+    fn send_d2c_message(&mut self, message: IotMessage) {
+        if self.pending_d2c_messages.len() == MAX_PENDING_D2C_MESSAGES {
+            warn!("drop oldest pending d2c message");
+            self.pending_d2c_messages.pop_front();
+        }
+
+        self.pending_d2c_messages.push_back(message);
+        self.flush_d2c_messages();
+    }
+
+    fn flush_d2c_messages(&mut self) {
+        let Some(client) = self.iothub_client.as_mut() else {
+            return;
+        };
+
+        if !self.authenticated {
+            return;
+        }
+
+        while let Some(message) = self.pending_d2c_messages.pop_front() {
+            if let Err(e) = client.send_d2c_message(message) {
+                warn!("send d2c message: {e:#}");
+                self.health.error(format!("send d2c message: {e:#}"));
+            }
+        }
+    }
+
+    fn flush_reported(&mut self) {
+        if !self.reported.is_pending() {
+            return;
+        }
+
+        let client = match self.iothub_client.as_mut() {
+            Some(client) if self.authenticated => client,
+            // a restart while disconnected mustn't lose e.g. the result of a deployment
+            _ => {
+                self.persist_reported_at.get_or_insert_with(|| {
+                    Instant::now() + Duration::from_secs(PERSIST_REPORTED_DELAY_SECS)
+                });
+                return;
+            }
+        };
+
+        match self.reported.flush(client.as_mut()) {
+            Ok(()) => {
+                self.persist_reported_at = None;
+                self.health.twin_synced()
+            }
+            Err(e) => {
+                warn!("{e:#}");
+                self.health.error(format!("{e:#}"));
+            }
+        }
+    }
+
     async fn handle_desired(
         &mut self,
         state: TwinUpdateState,
         desired: serde_json::Value,
     ) -> Result<()> {
-        info!("desired: {state:#?}, {desired}");
+        let mut logged = desired.clone();
+        diagnostics::redact_sas_url(&mut logged);
+        info!("desired: {state:#?}, {logged}");
 
-        match state {
+        // an invalid service_config must not prevent e.g. a deployment
+        let (desired, complete, service_config) = match state {
             TwinUpdateState::Partial => {
-                /*                 if let Some(gc) = desired.get("general_consent") {
-                    self.feature::<DeviceUpdateConsent>()?
-                        .update_general_consent(gc.as_array())
-                        .await?;
-                }
+                let service_config = match desired.get("service_config") {
+                    Some(sc) => {
+                        let mut overrides = self.service_config_overrides.clone();
+                        service_config::merge(&mut overrides, sc);
+                        self.update_service_config(overrides).await
+                    }
+                    None => Ok(()),
+                };
 
-                if let Some(inf) = desired.get("include_network_filter") {
-                    self.feature_mut::<NetworkStatus>()?
-                        .update_include_network_filter(inf.as_array())
-                        .await?;
-                } */
+                (desired, false, service_config)
             }
             TwinUpdateState::Complete => {
-                /*                 if desired.get("desired").is_none() {
+                let Some(desired) = desired.get("desired") else {
                     bail!("handle_desired: 'desired' missing while TwinUpdateState::Complete")
-                }
+                };
 
-                self.feature::<DeviceUpdateConsent>()?
-                    .update_general_consent(desired["desired"]["general_consent"].as_array())
-                    .await?;
+                let service_config = self
+                    .update_service_config(desired["service_config"].clone())
+                    .await;
 
-                self.feature_mut::<NetworkStatus>()?
-                    .update_include_network_filter(
-                        desired["desired"]["include_network_filter"].as_array(),
-                    )
-                    .await?; */
+                (desired.clone(), true, service_config)
             }
+        };
+
+        let features = self.features.handle_desired(&desired, complete).await;
+
+        match (service_config, features) {
+            (Err(e), Ok(())) => Err(e),
+            (Err(e), Err(features)) => Err(anyhow!("{e:#}, {features:#}")),
+            (Ok(()), features) => features,
         }
+    }
+
+    // the overrides are only taken over if valid, so that we keep a consistent config
+    async fn update_service_config(&mut self, overrides: serde_json::Value) -> Result<()> {
+        let service_config = service_config::get().with_overrides(&overrides)?;
+
+        self.tx_reported_properties
+            .send(json!({ "service_config": service_config.overridable()? }))
+            .await?;
+
+        info!("service config overrides: {overrides}");
+
+        self.tx_download_config
+            .send_replace(service_config.download.clone());
+        self.service_config = service_config;
+        self.service_config_overrides = overrides;
+
         Ok(())
     }
 
+    async fn handle_direct_method(&mut self, method: DirectMethod) {
+        info!("direct method: {}({})", method.name, method.payload);
+
+        let result = self
+            .features
+            .handle_direct_method(&method.name, &method.payload)
+            .await;
+
+        if let Err(e) = &result {
+            warn!("direct method {}: {e:#}", method.name);
+            self.health
+                .error(format!("direct method {}: {e:#}", method.name));
+        }
+
+        if method.responder.send(result.map(Some)).is_err() {
+            warn!("direct method {}: cannot send response", method.name);
+        }
+    }
+
+    async fn handle_event(&mut self, index: usize, event: Event) {
+        if let Err(e) = self.features.handle_event(index, event).await {
+            warn!("{e:#}");
+            self.health.error(format!("{e:#}"));
+        }
+    }
+
+    async fn handle_reboot(&mut self, reboot: Reboot) -> Result<()> {
+        info!("reboot requested: {reboot:?}");
+
+        // make sure all pending reported properties, e.g. the pending reboot state,
+        // are handed over to the iothub client or at least persisted before we go down
+        self.shutdown().await;
+
+        systemd::reboot().await
+    }
+
     pub async fn run() -> Result<()> {
         let (tx_connection_status, mut rx_connection_status) = mpsc::channel(100);
         let (tx_twin_desired, mut rx_twin_desired) = mpsc::channel(100);
+        let (tx_direct_method, mut rx_direct_method) = mpsc::channel(100);
 
         let mut signals = Signals::new(TERM_SIGNALS)?;
 
-        let mut sd_notify_interval = if let Some(micros) = WatchdogManager::init() {
+        let watchdog_interval_micros = WatchdogManager::init();
+        let mut sd_notify_interval = if let Some(micros) = watchdog_interval_micros {
             let micros = micros / 2;
             debug!("trigger watchdog interval: {micros}µs");
             Some(interval(Duration::from_micros(micros)))
@@ -123,43 +460,124 @@ impl Twin {
             None
         };
 
+        let mut health_interval = interval(Duration::from_secs(SERVICE_HEALTH_INTERVAL_SECS));
+
+        info!("service config: {:?}", service_config::get());
+
+        let paths = service_config::paths();
+        let du_config = DuConfig::load(&paths.du_config)?;
+        let agent_config = du_config.agent(&du_config::agent_name()?)?;
+
+        agent_config.ensure_runas()?;
+
         let builder = IotHubClient::builder()
             .observe_connection_state(tx_connection_status)
             .observe_desired_properties(tx_twin_desired)
+            .observe_direct_methods(tx_direct_method)
             .pnp_model_id("dtmi:azure:iot:deviceUpdateModel;3");
 
-        let mut twin = if cfg!(feature = "mock") {
-            Self::new(
-                builder
-                    .build_module_client(&std::env::var("CONNECTION_STRING").unwrap())
-                    .unwrap(),
-            )?
-        } else {
-            Self::new(builder.build_module_client_from_identity().await.unwrap())?
-        };
+        let mut twin = Self::new(&du_config, agent_config, watchdog_interval_micros)?;
+        let mut events = twin.features.event_streams()?;
+
+        // every exit of the event loop but termination signal or reboot is an error
+        let result: Result<()> = async {
+            loop {
+                select! (
+                    _ =  notify_some_interval(&mut sd_notify_interval) => {
+                        WatchdogManager::notify()?;
+                    },
+                    signal = signals.next() => {
+                        info!("terminate: {signal:?}");
+                        signals.handle().close();
+                        return Ok(())
+                    },
+                    status = rx_connection_status.recv() => {
+                        let status = status.context("connection status channel closed")?;
+                        twin.handle_connection_status(status).await?;
+                    },
+                    desired = rx_twin_desired.recv() => {
+                        let (state, desired) = desired.context("desired properties channel closed")?;
+                        match twin.handle_desired(state, desired).await {
+                            Ok(()) => twin.health.twin_synced(),
+                            Err(e) => {
+                                error!("twin update desired properties: {e:#}");
+                                twin.health.error(format!("twin update desired properties: {e:#}"));
+                            }
+                        }
+                    },
+                    method = rx_direct_method.recv() => {
+                        twin.handle_direct_method(method.context("direct method channel closed")?).await;
+                    },
+                    // both channels are closed if device_update is disabled
+                    Some(message) = twin.rx_d2c_messages.recv() => {
+                        twin.send_d2c_message(message)
+                    },
+                    reported = twin.rx_reported_properties.recv() => {
+                        twin.report(reported.context("reported properties channel closed")?)
+                    },
+                    _ = sleep_until_some(twin.reconnect_at) => {
+                        twin.connect(&builder, agent_config).await?;
+                    },
+                    _ = sleep_until_some(twin.persist_reported_at) => {
+                        twin.persist_reported();
+                    },
+                    _ = health_interval.tick() => {
+                        twin.report_health().await?;
+                    },
+                    Some((index, event)) = events.next() => {
+                        twin.handle_event(index, event).await;
+                    },
+                    Some(reboot) = twin.rx_request_reboot.recv() => {
+                        twin.handle_reboot(reboot).await?;
+                        return Ok(())
+                    },
+                );
+            }
+        }
+        .await;
+
+        twin.shutdown().await;
+
+        result
+    }
+}
 
-        loop {
-            select! (
-                _ =  notify_some_interval(&mut sd_notify_interval) => {
-                    WatchdogManager::notify()?;
-                },
-                _ = signals.next() => {
-                    signals.handle().close();
-                    twin.iothub_client.shutdown().await;
-                    return Ok(())
-                },
-                status = rx_connection_status.recv() => {
-                    twin.handle_connection_status(status.unwrap()).await?;
-                },
-                desired = rx_twin_desired.recv() => {
-                    let (state, desired) = desired.unwrap();
-                    twin.handle_desired(state, desired).await.unwrap_or_else(|e| error!("twin update desired properties: {e:#}"));
-                },
-                reported = twin.rx_reported_properties.recv() => {
-                    twin.iothub_client.twin_report(reported.unwrap())?
-                },
-            );
+async fn build_client(
+    builder: &IotHubClientBuilder,
+    agent_config: &AgentConfig,
+) -> Result<Box<dyn IotHub>> {
+    let connection_source = if cfg!(feature = "mock") {
+        ConnectionSource {
+            connection_type: ConnectionType::String,
+            connection_data: std::env::var("CONNECTION_STRING")
+                .context("build_client: CONNECTION_STRING")?,
         }
+    } else {
+        agent_config.connection_source.clone()
+    };
+
+    let client_kind = connection_source.client_kind();
+
+    info!(
+        "create {client_kind:?} client via {:?}",
+        connection_source.connection_type
+    );
+
+    match (connection_source.connection_type, client_kind) {
+        (ConnectionType::Ais, _) => builder.build_module_client_from_identity().await,
+        (ConnectionType::String, ClientKind::Module) => {
+            builder.build_module_client(&connection_source.connection_data)
+        }
+        (ConnectionType::String, ClientKind::Device) => {
+            builder.build_device_client(&connection_source.connection_data)
+        }
+    }
+}
+
+fn sleep_until_some(deadline: Option<Instant>) -> impl Future<Output = ()> {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).left_future(),
+        None => pending().right_future(),
     }
 }
 
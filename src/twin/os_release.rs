@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use std::{collections::HashMap, path::Path};
+
+// see https://www.freedesktop.org/software/systemd/man/os-release.html
+pub struct OsRelease {
+    values: HashMap<String, String>,
+}
+
+impl OsRelease {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path).context("cannot read os-release")?;
+
+        Ok(Self::parse(&content))
+    }
+
+    // invalid lines are ignored as recommended by the os-release specification
+    pub fn parse(content: &str) -> Self {
+        let values = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim().to_owned(), unquote(value.trim())))
+            .collect();
+
+        OsRelease { values }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .get(key)
+            .map(String::as_str)
+            .filter(|value| !value.is_empty())
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let unquoted = ['"', '\'']
+        .iter()
+        .find_map(|quote| {
+            value
+                .strip_prefix(*quote)
+                .and_then(|value| value.strip_suffix(*quote))
+        })
+        .unwrap_or(value);
+
+    unquoted
+        .replace("\\\"", "\"")
+        .replace("\\$", "$")
+        .replace("\\`", "`")
+        .replace("\\\\", "\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_quoted_values() {
+        let os_release = OsRelease::parse(
+            "# comment\nNAME=\"omnect os\"\nID=omnect-os\nVERSION_ID='4.0.17'\nBUILD_ID=\"\"\ninvalid\n",
+        );
+
+        assert_eq!(os_release.get("NAME"), Some("omnect os"));
+        assert_eq!(os_release.get("ID"), Some("omnect-os"));
+        assert_eq!(os_release.get("VERSION_ID"), Some("4.0.17"));
+        assert_eq!(os_release.get("BUILD_ID"), None);
+        assert_eq!(os_release.get("invalid"), None);
+    }
+}
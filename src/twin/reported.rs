@@ -0,0 +1,248 @@
+use super::service_config::{self, merge};
+use anyhow::{Context, Result};
+use azure_iot_sdk::client::IotHub;
+use log::{debug, warn};
+use serde_json::Value;
+use std::{fs, path::PathBuf};
+
+pub const REPORTED_FILE: &str = "reported.json";
+
+/*
+ * Reported properties are merged into a pending patch, which is sent while we are connected.
+ * In order to spare the flash the state is only persisted if it couldn't be sent, i.e. if a
+ * flush failed, while we are disconnected (debounced by the caller) or on shutdown, so that
+ * it can be restored after a restart.
+ */
+pub struct ReportedCache {
+    path: PathBuf,
+    // state as known by the iothub
+    reported: Value,
+    // not sent yet, null values are kept since they remove properties
+    pending: Value,
+    // the persisted state is outdated once everything got sent
+    persisted: bool,
+}
+
+impl ReportedCache {
+    // the persisted state is sent again on first flush, e.g. in case it got lost before restart
+    pub fn load() -> Self {
+        let path = service_config::paths().state_dir.join(REPORTED_FILE);
+
+        let pending = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| {
+                serde_json::from_str(&content)
+                    .map_err(|e| warn!("ignore invalid {}: {e}", path.display()))
+                    .ok()
+            })
+            .unwrap_or(Value::Null);
+
+        ReportedCache {
+            persisted: path.exists(),
+            path,
+            reported: Value::Null,
+            pending,
+        }
+    }
+
+    pub fn push(&mut self, patch: Value) {
+        combine(&mut self.pending, patch);
+    }
+
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_null()
+    }
+
+    // the whole state is sent with the next flush, e.g. after a new client was created
+    pub fn invalidate(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        self.pending = std::mem::take(&mut self.reported);
+        combine(&mut self.pending, pending);
+    }
+
+    // on error the pending patch is kept and sent with the next flush
+    pub fn flush(&mut self, client: &mut dyn IotHub) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        if let Some(patch) = minimal(&self.reported, &pending) {
+            debug!("flush reported: {patch}");
+
+            if let Err(e) = client.twin_report(patch) {
+                self.pending = pending;
+
+                if let Err(e) = self.persist() {
+                    warn!("{e:#}");
+                }
+
+                return Err(e).context("flush reported");
+            }
+        }
+
+        merge(&mut self.reported, &pending);
+
+        if self.persisted {
+            match fs::remove_file(&self.path) {
+                Ok(()) => self.persisted = false,
+                Err(e) => warn!("flush: cannot remove {}: {e}", self.path.display()),
+            }
+        }
+
+        Ok(())
+    }
+
+    // for pending properties which couldn't be sent yet
+    pub fn persist(&mut self) -> Result<()> {
+        if !self.is_pending() {
+            return Ok(());
+        }
+
+        let mut state = self.reported.clone();
+        merge(&mut state, &self.pending);
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).context("persist: cannot create state dir")?;
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec(&state)?).context("persist: cannot write")?;
+        fs::rename(&tmp_path, &self.path).context("persist: cannot rename")?;
+
+        self.persisted = true;
+
+        Ok(())
+    }
+}
+
+// merges patch into pending, unlike merge() null values are kept
+fn combine(pending: &mut Value, patch: Value) {
+    match (pending, patch) {
+        (Value::Object(pending), Value::Object(patch)) => {
+            for (key, value) in patch {
+                match pending.get_mut(&key) {
+                    Some(pending) if value.is_object() => combine(pending, value),
+                    _ => {
+                        pending.insert(key, value);
+                    }
+                }
+            }
+        }
+        (pending, patch) => *pending = patch,
+    }
+}
+
+// the part of pending which changes reported, None if nothing changes
+fn minimal(reported: &Value, pending: &Value) -> Option<Value> {
+    let (Some(reported), Some(pending)) = (reported.as_object(), pending.as_object()) else {
+        return (reported != pending).then(|| pending.clone());
+    };
+
+    let mut patch: serde_json::Map<String, Value> = pending
+        .iter()
+        .filter_map(|(key, value)| {
+            let patch = match reported.get(key) {
+                Some(reported) if value.is_object() => minimal(reported, value),
+                Some(reported) => (reported != value).then(|| value.clone()),
+                // removing an unknown property is a no-op
+                None => (!value.is_null()).then(|| value.clone()),
+            };
+
+            patch.map(|patch| (key.clone(), patch))
+        })
+        .collect();
+
+    if patch.is_empty() {
+        return None;
+    }
+
+    // components have to be marked in every patch
+    if let Some(marker) = pending.get("__t") {
+        patch.insert("__t".to_owned(), marker.clone());
+    }
+
+    Some(patch.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn combine_keeps_null() {
+        let mut pending = json!({"a": {"b": 1}, "c": 2});
+
+        combine(
+            &mut pending,
+            json!({"a": {"b": null, "d": 3}, "c": {"e": 4}}),
+        );
+
+        assert_eq!(pending, json!({"a": {"b": null, "d": 3}, "c": {"e": 4}}));
+    }
+
+    #[test]
+    fn minimal_patch() {
+        let reported = json!({"a": {"b": 1, "c": 2}, "d": 3});
+
+        assert_eq!(
+            minimal(
+                &reported,
+                &json!({"a": {"b": 1, "c": 5}, "d": 3, "e": null})
+            ),
+            Some(json!({"a": {"c": 5}}))
+        );
+        assert_eq!(
+            minimal(&reported, &json!({"a": {"b": null}})),
+            Some(json!({"a": {"b": null}}))
+        );
+        assert_eq!(minimal(&reported, &json!({"d": 3})), None);
+        assert_eq!(
+            minimal(&Value::Null, &json!({"d": 3})),
+            Some(json!({"d": 3}))
+        );
+
+        let reported = json!({"c": {"__t": "c", "a": 1, "b": 2}});
+        assert_eq!(
+            minimal(&reported, &json!({"c": {"__t": "c", "a": 1, "b": 3}})),
+            Some(json!({"c": {"__t": "c", "b": 3}}))
+        );
+    }
+
+    #[test]
+    fn invalidate_sends_everything() {
+        let mut cache = ReportedCache {
+            path: PathBuf::new(),
+            reported: json!({"a": 1, "b": 2}),
+            pending: json!({"b": 3}),
+            persisted: false,
+        };
+
+        cache.invalidate();
+
+        assert_eq!(cache.pending, json!({"a": 1, "b": 3}));
+        assert!(cache.reported.is_null());
+    }
+
+    #[test]
+    fn persist_only_pending_state() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut cache = ReportedCache {
+            path: tmp_dir.path().join(REPORTED_FILE),
+            reported: json!({"a": 1}),
+            pending: Value::Null,
+            persisted: false,
+        };
+
+        cache.persist().unwrap();
+        assert!(!cache.path.exists());
+
+        cache.push(json!({"b": 2}));
+        assert!(!cache.path.exists());
+
+        cache.persist().unwrap();
+        assert!(cache.persisted);
+        assert_eq!(
+            serde_json::from_str::<Value>(&fs::read_to_string(&cache.path).unwrap()).unwrap(),
+            json!({"a": 1, "b": 2})
+        );
+    }
+}
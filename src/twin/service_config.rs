@@ -0,0 +1,529 @@
+use super::{
+    deployment,
+    maintenance_window::{MaintenanceWindows, MaintenanceWindowsConfig},
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+const CONFIG_PATH_ENV: &str = "UPDATE_SERVICE_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "/etc/omnect/update-service.toml";
+
+// sections that can be overridden by the "service_config" desired property
+const OVERRIDABLE_SECTIONS: [&str; 2] = ["download", "retry"];
+
+static SERVICE_CONFIG: OnceLock<ServiceConfig> = OnceLock::new();
+
+/*
+ * Every path can be set in the [paths] section of the service config file and is
+ * overruled by its environment variable, e.g.:
+ * [paths]
+ * du_config = "/etc/adu/du-config.json"
+ * state_dir = "/var/lib/omnect-update-service"
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub du_config: PathBuf,
+    pub sw_versions: PathBuf,
+    pub os_release: PathBuf,
+    pub state_dir: PathBuf,
+    pub download_dir: PathBuf,
+    pub rollback_hook: PathBuf,
+    // trusted keys of update manifest signatures, see manifest_signature
+    pub root_keys: PathBuf,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            du_config: "/etc/adu/du-config.json".into(),
+            sw_versions: "/etc/sw-versions".into(),
+            os_release: "/etc/os-release".into(),
+            state_dir: "/var/lib/omnect-update-service".into(),
+            download_dir: "/var/lib/omnect-update-service/download".into(),
+            rollback_hook: "/usr/libexec/omnect-update-service/rollback-hook".into(),
+            root_keys: "/etc/omnect/adu-root-keys.json".into(),
+        }
+    }
+}
+
+impl PathsConfig {
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        for (key, path) in [
+            ("DU_CONFIG_PATH", &mut self.du_config),
+            ("SW_VERSIONS_PATH", &mut self.sw_versions),
+            ("OS_RELEASE_PATH", &mut self.os_release),
+            ("STATE_DIR_PATH", &mut self.state_dir),
+            ("DOWNLOAD_DIR_PATH", &mut self.download_dir),
+            ("ROLLBACK_HOOK_PATH", &mut self.rollback_hook),
+            ("ROOT_KEYS_PATH", &mut self.root_keys),
+        ] {
+            if let Some(value) = var(key).filter(|value| !value.is_empty()) {
+                *path = value.into();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadConfig {
+    pub timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            timeout_secs: 3600,
+            connect_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    // 0 retries forever
+    pub max_attempts: u32,
+    pub initial_delay_secs: u64,
+    pub max_delay_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 0,
+            initial_delay_secs: 5,
+            max_delay_secs: 300,
+        }
+    }
+}
+
+impl RetryConfig {
+    // exponential backoff, attempt starts with 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial_delay_secs
+            .saturating_mul(2u64.saturating_pow(attempt));
+
+        Duration::from_secs(delay.min(self.max_delay_secs))
+    }
+
+    pub fn exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts != 0 && attempt >= self.max_attempts
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HandlersConfig {
+    // update types we accept, e.g. "microsoft/swupdate:2"
+    pub enabled: Vec<String>,
+}
+
+impl Default for HandlersConfig {
+    fn default() -> Self {
+        HandlersConfig {
+            enabled: vec![
+                "microsoft/swupdate:1".to_owned(),
+                "microsoft/swupdate:2".to_owned(),
+                "omnect/swupdate_consent:1".to_owned(),
+            ],
+        }
+    }
+}
+
+impl HandlersConfig {
+    pub fn is_enabled(&self, update_type: &str) -> bool {
+        self.enabled.iter().any(|enabled| enabled == update_type)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesConfig {
+    // features which are not loaded, e.g. ["diagnostics"]
+    pub disabled: Vec<String>,
+}
+
+impl FeaturesConfig {
+    pub fn is_enabled(&self, name: &str) -> bool {
+        !self.disabled.iter().any(|disabled| disabled == name)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiagnosticsConfig {
+    // systemd units whose journal is uploaded
+    pub journal_units: Vec<String>,
+    pub journal_lines: u32,
+    // e.g. swupdate logs, missing files are skipped; the state directory is always included
+    pub files: Vec<PathBuf>,
+    pub upload_timeout_secs: u64,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        DiagnosticsConfig {
+            journal_units: vec![
+                "omnect-update-service.service".to_owned(),
+                "swupdate.service".to_owned(),
+            ],
+            journal_lines: 10000,
+            files: vec!["/var/log/swupdate.log".into()],
+            upload_timeout_secs: 600,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceInfoConfig {
+    // total storage is summed up over all mount points, e.g. ["/", "/mnt/data"]
+    pub mount_points: Vec<PathBuf>,
+    // 0 disables periodic device_status reports
+    pub status_interval_secs: u64,
+    // device_status is only reported if free memory or storage changed by more than this
+    pub status_threshold_percent: u64,
+}
+
+impl Default for DeviceInfoConfig {
+    fn default() -> Self {
+        DeviceInfoConfig {
+            mount_points: vec!["/".into()],
+            status_interval_secs: 300,
+            status_threshold_percent: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    // env_logger filter, e.g. "info" or "info,zbus=warn", RUST_LOG takes precedence
+    pub level: Option<String>,
+}
+
+/*
+ * /etc/omnect/update-service.toml, all sections and values are optional:
+ * [paths]              see PathsConfig, the state directory is configured here
+ * [download]           timeouts of update downloads
+ * [retry]              backoff of connection and identity service retries
+ * [maintenance_windows] same format as the desired property, which replaces it if set
+ * [handlers]           enabled update types
+ * [features]           disabled twin features
+ * [diagnostics]        log sources of the diagnostics log upload
+ * [device_info]        storage mount points and device_status reports
+ * [logging]            log level
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub paths: PathsConfig,
+    pub download: DownloadConfig,
+    pub retry: RetryConfig,
+    pub maintenance_windows: MaintenanceWindowsConfig,
+    pub handlers: HandlersConfig,
+    pub features: FeaturesConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub device_info: DeviceInfoConfig,
+    pub logging: LoggingConfig,
+}
+
+impl ServiceConfig {
+    // a missing config file is fine, we use defaults then
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let mut config = if path.exists() {
+            Self::parse(&std::fs::read_to_string(path).context("cannot read service config")?)?
+        } else {
+            ServiceConfig::default()
+        };
+
+        config.paths.apply_env(|key| env::var(key).ok());
+
+        Ok(config)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let config: ServiceConfig =
+            serde_path_to_error::deserialize(toml::Deserializer::new(content))
+                .map_err(|e| anyhow!("service config: {}: {}", e.path(), e.inner().message()))?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /*
+     * overrides contain some sections of the config, e.g. {"retry": {"max_attempts": 10}},
+     * which are merged into a copy of this config.
+     */
+    pub fn with_overrides(&self, overrides: &Value) -> Result<Self> {
+        if overrides.is_null() {
+            return Ok(self.clone());
+        }
+
+        let overrides = overrides
+            .as_object()
+            .context("service config overrides: object expected")?;
+
+        let mut config = serde_json::to_value(self)?;
+
+        for (section, value) in overrides {
+            ensure!(
+                OVERRIDABLE_SECTIONS.contains(&section.as_str()),
+                "service config overrides: section \"{section}\" cannot be overridden"
+            );
+
+            merge(&mut config[section], value);
+        }
+
+        let config: ServiceConfig = serde_path_to_error::deserialize(config)
+            .map_err(|e| anyhow!("service config overrides: {}: {}", e.path(), e.inner()))?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // the sections which can be overridden, as reported to the cloud
+    pub fn overridable(&self) -> Result<Value> {
+        let config = serde_json::to_value(self)?;
+
+        Ok(OVERRIDABLE_SECTIONS
+            .iter()
+            .map(|section| (section.to_string(), config[section].clone()))
+            .collect::<serde_json::Map<_, _>>()
+            .into())
+    }
+
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            0 < self.retry.initial_delay_secs
+                && self.retry.initial_delay_secs <= self.retry.max_delay_secs,
+            "service config: retry: 0 < initial_delay_secs <= max_delay_secs expected"
+        );
+
+        ensure!(
+            0 < self.download.timeout_secs && 0 < self.download.connect_timeout_secs,
+            "service config: download: timeouts must not be 0"
+        );
+
+        ensure!(
+            0 < self.diagnostics.upload_timeout_secs,
+            "service config: diagnostics: upload_timeout_secs must not be 0"
+        );
+
+        ensure!(
+            !self.device_info.mount_points.is_empty(),
+            "service config: device_info: mount_points must not be empty"
+        );
+
+        ensure!(
+            self.device_info.status_threshold_percent <= 100,
+            "service config: device_info: status_threshold_percent <= 100 expected"
+        );
+
+        for update_type in &self.handlers.enabled {
+            ensure!(
+                deployment::is_supported(update_type),
+                "service config: handlers: unsupported update type \"{update_type}\""
+            );
+        }
+
+        MaintenanceWindows::from_config(self.maintenance_windows.clone())?;
+
+        Ok(())
+    }
+}
+
+// json merge patch as of RFC 7386, null removes a value
+pub fn merge(target: &mut Value, patch: &Value) {
+    let Some(patch) = patch.as_object() else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+
+    if let Some(target) = target.as_object_mut() {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge(target.entry(key.as_str()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+// to be called once at startup, before any other function of this module
+pub fn init() -> Result<&'static ServiceConfig> {
+    let path = env::var(CONFIG_PATH_ENV).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_owned());
+    let config = ServiceConfig::load(path)?;
+
+    ensure!(
+        SERVICE_CONFIG.set(config).is_ok(),
+        "service config: already initialized"
+    );
+
+    Ok(get())
+}
+
+// falls back to defaults and environment if init() wasn't called, e.g. in tests
+pub fn get() -> &'static ServiceConfig {
+    SERVICE_CONFIG.get_or_init(|| {
+        let mut config = ServiceConfig::default();
+        config.paths.apply_env(|key| env::var(key).ok());
+
+        // tests must neither depend on nor change the state of the machine they run on
+        #[cfg(test)]
+        {
+            let tmp_dir =
+                env::temp_dir().join(format!("omnect-update-service-test-{}", std::process::id()));
+
+            config.paths = PathsConfig {
+                du_config: "testfiles/du-config.json".into(),
+                sw_versions: "testfiles/sw-versions".into(),
+                os_release: "testfiles/os-release".into(),
+                state_dir: tmp_dir.join("state"),
+                download_dir: tmp_dir.join("download"),
+                rollback_hook: "testfiles/rollback-hook".into(),
+                root_keys: "testfiles/signature/root-keys.json".into(),
+            };
+        }
+
+        config
+    })
+}
+
+pub fn paths() -> &'static PathsConfig {
+    &get().paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_paths_with_defaults() {
+        let config = ServiceConfig::load("testfiles/update-service.toml").unwrap();
+
+        assert_eq!(
+            config.paths.du_config,
+            Path::new("testfiles/du-config.json")
+        );
+        assert_eq!(
+            config.paths.state_dir,
+            Path::new("/tmp/omnect-update-service")
+        );
+        assert_eq!(
+            config.paths.rollback_hook,
+            Path::new("testfiles/rollback-hook")
+        );
+
+        assert_eq!(config.retry.max_attempts, 10);
+        assert_eq!(
+            config.retry.max_delay_secs,
+            RetryConfig::default().max_delay_secs
+        );
+        assert!(config.handlers.is_enabled("microsoft/swupdate:2"));
+        assert!(!config.handlers.is_enabled("microsoft/script:1"));
+        assert_eq!(config.logging.level.as_deref(), Some("debug"));
+        assert!(!config.features.is_enabled("diagnostics"));
+        assert!(config.features.is_enabled("device_update"));
+        assert_eq!(config.device_info.status_interval_secs, 60);
+        assert_eq!(
+            config.device_info.mount_points,
+            DeviceInfoConfig::default().mount_points
+        );
+
+        assert!(ServiceConfig::parse("[paths]\nunknown = \"/tmp\"").is_err());
+        assert!(ServiceConfig::parse("[retry]\ninitial_delay_secs = 0").is_err());
+        assert!(ServiceConfig::parse("[maintenance_windows]\nphases = [\"install\"]").is_err());
+        assert!(ServiceConfig::parse("[handlers]\nenabled = [\"microsoft/script:1\"]").is_err());
+        assert!(ServiceConfig::parse("[device_info]\nmount_points = []").is_err());
+        assert!(ServiceConfig::parse("[device_info]\nstatus_threshold_percent = 101").is_err());
+        assert_eq!(
+            ServiceConfig::load("testfiles/missing.toml").unwrap().paths,
+            PathsConfig::default()
+        );
+    }
+
+    #[test]
+    fn env_overrules_config_file() {
+        let mut paths = ServiceConfig::parse("[paths]\nstate_dir = \"/data/state\"")
+            .unwrap()
+            .paths;
+
+        paths.apply_env(|key| match key {
+            "STATE_DIR_PATH" => Some("/run/state".to_owned()),
+            "SW_VERSIONS_PATH" => Some(String::new()),
+            _ => None,
+        });
+
+        assert_eq!(paths.state_dir, Path::new("/run/state"));
+        assert_eq!(paths.sw_versions, PathsConfig::default().sw_versions);
+    }
+
+    #[test]
+    fn desired_overrides() {
+        let config =
+            ServiceConfig::parse("[retry]\nmax_attempts = 10\nmax_delay_secs = 60").unwrap();
+
+        let overridden = config
+            .with_overrides(&serde_json::json!({"retry": {"max_attempts": 3}}))
+            .unwrap();
+        assert_eq!(overridden.retry.max_attempts, 3);
+        assert_eq!(overridden.retry.max_delay_secs, 60);
+        assert_eq!(config.with_overrides(&Value::Null).unwrap(), config);
+
+        assert!(config
+            .with_overrides(&serde_json::json!({"paths": {"state_dir": "/tmp"}}))
+            .is_err());
+        assert!(config
+            .with_overrides(&serde_json::json!({"retry": {"max_attempts": "3"}}))
+            .is_err());
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let retry = RetryConfig {
+            max_attempts: 3,
+            initial_delay_secs: 5,
+            max_delay_secs: 30,
+        };
+
+        assert_eq!(retry.delay(0), Duration::from_secs(5));
+        assert_eq!(retry.delay(2), Duration::from_secs(20));
+        assert_eq!(retry.delay(40), Duration::from_secs(30));
+        assert!(!retry.exhausted(2));
+        assert!(retry.exhausted(3));
+        assert!(!RetryConfig::default().exhausted(u32::MAX));
+    }
+
+    #[test]
+    fn merge_patch() {
+        let mut target = serde_json::json!({"a": {"b": 1, "c": 2}, "d": 3});
+
+        merge(
+            &mut target,
+            &serde_json::json!({"a": {"b": null, "e": 4}, "d": [5]}),
+        );
+
+        assert_eq!(target, serde_json::json!({"a": {"c": 2, "e": 4}, "d": [5]}));
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::path::Path;
+
+/*
+ * /etc/sw-versions lists one component per line, e.g.:
+ * OMNECT-gateway-devel 4.0.17.123456
+ * bootloader 2023.04
+ * The first component describes the os image.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwVersions {
+    components: Vec<(String, String)>,
+}
+
+impl SwVersions {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let content = std::fs::read_to_string(path).context("cannot read sw-versions")?;
+
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut components: Vec<(String, String)> = vec![];
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut tokens = line.split_whitespace();
+
+            let (Some(name), Some(version), None) = (tokens.next(), tokens.next(), tokens.next())
+            else {
+                bail!("sw-versions: line {}: expected \"<name> <version>\"", i + 1);
+            };
+
+            ensure!(
+                valid_version(version),
+                "sw-versions: line {}: invalid version \"{version}\"",
+                i + 1
+            );
+
+            ensure!(
+                components.iter().all(|(n, _)| n != name),
+                "sw-versions: line {}: duplicate component \"{name}\"",
+                i + 1
+            );
+
+            components.push((name.to_owned(), version.to_owned()));
+        }
+
+        ensure!(!components.is_empty(), "sw-versions: no component found");
+
+        Ok(SwVersions { components })
+    }
+
+    // only describes the os image, e.g. if derived from /etc/os-release
+    pub fn from_os(name: impl Into<String>, version: impl Into<String>) -> Self {
+        SwVersions {
+            components: vec![(name.into(), version.into())],
+        }
+    }
+
+    // name and version of the os image
+    pub fn os(&self) -> (&str, &str) {
+        let (name, version) = &self.components[0];
+        (name, version)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.components
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, version)| version.as_str())
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.components
+            .iter()
+            .map(|(name, version)| (name.as_str(), version.as_str()))
+    }
+
+    /*
+     * Installed criteria are either "<name> <version>" of a component or only a version,
+     * which is then compared against the os image.
+     */
+    pub fn satisfies(&self, installed_criteria: &str) -> bool {
+        let mut tokens = installed_criteria.split_whitespace();
+
+        match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some(name), Some(version), None) => self.get(name) == Some(version),
+            (Some(version), None, None) => self.os().1 == version,
+            _ => false,
+        }
+    }
+}
+
+fn valid_version(version: &str) -> bool {
+    version.starts_with(|c: char| c.is_ascii_digit())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".-+_~".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multiple_components() {
+        let sw_versions =
+            SwVersions::parse("OMNECT-gateway-devel 4.0.17.123456\n\n  bootloader   2023.04  \n")
+                .unwrap();
+
+        assert_eq!(sw_versions.os(), ("OMNECT-gateway-devel", "4.0.17.123456"));
+        assert_eq!(sw_versions.get("bootloader"), Some("2023.04"));
+        assert_eq!(sw_versions.components().count(), 2);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(SwVersions::parse("").is_err());
+        assert!(SwVersions::parse("name").is_err());
+        assert!(SwVersions::parse("name 1.0 extra").is_err());
+        assert!(SwVersions::parse("name v1.0").is_err());
+        assert!(SwVersions::parse("name 1.0\nname 1.1").is_err());
+    }
+
+    #[test]
+    fn installed_criteria() {
+        let sw_versions = SwVersions::parse("os 4.0.17\nbootloader 2023.04").unwrap();
+
+        assert!(sw_versions.satisfies("os 4.0.17"));
+        assert!(sw_versions.satisfies("4.0.17"));
+        assert!(sw_versions.satisfies("bootloader 2023.04"));
+        assert!(!sw_versions.satisfies("os 4.0.18"));
+        assert!(!sw_versions.satisfies("unknown 4.0.17"));
+        assert!(!sw_versions.satisfies(""));
+    }
+}
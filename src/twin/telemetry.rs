@@ -0,0 +1,133 @@
+use super::workflow::Workflow;
+use anyhow::{Context, Result};
+use azure_iot_sdk::client::IotMessage;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::mpsc::Sender;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeploymentEvent {
+    Started,
+    Downloaded,
+    Installed,
+    Applied,
+    Rebooted,
+    Succeeded,
+    Failed,
+}
+
+/*
+ * Deployment events are sent as device to cloud messages, e.g.:
+ * {
+ *     "event": "downloaded",
+ *     "workflow_id": "...",
+ *     "update_id": {...},
+ *     "timestamp": "2023-10-01T12:00:00Z",
+ *     "elapsed_secs": 42,          since the deployment started
+ *     "phase_duration_secs": 40,   since the previous event
+ *     "bytes_transferred": 123456,
+ *     "details": "..."             optional
+ * }
+ */
+#[derive(Clone)]
+pub struct Telemetry {
+    tx_d2c_messages: Sender<IotMessage>,
+}
+
+impl Telemetry {
+    pub fn new(tx_d2c_messages: Sender<IotMessage>) -> Self {
+        Telemetry { tx_d2c_messages }
+    }
+
+    // telemetry must never break a deployment, so errors are only logged
+    pub async fn deployment_event(
+        &self,
+        workflow: &mut Workflow,
+        event: DeploymentEvent,
+        details: Option<&str>,
+    ) {
+        if let Err(e) = self.send_deployment_event(workflow, event, details).await {
+            warn!("deployment event {event:?}: {e:#}");
+        }
+    }
+
+    async fn send_deployment_event(
+        &self,
+        workflow: &mut Workflow,
+        event: DeploymentEvent,
+        details: Option<&str>,
+    ) -> Result<()> {
+        let now = OffsetDateTime::now_utc();
+        let started_at = *workflow.started_at.get_or_insert(now.unix_timestamp());
+        let phase_changed_at = workflow
+            .phase_changed_at
+            .replace(now.unix_timestamp())
+            .unwrap_or(started_at);
+
+        let mut body = json!({
+            "event": event,
+            "workflow_id": workflow.id,
+            "update_id": workflow.update_id,
+            "timestamp": now.format(&Rfc3339)?,
+            "elapsed_secs": now.unix_timestamp() - started_at,
+            "phase_duration_secs": now.unix_timestamp() - phase_changed_at,
+            "bytes_transferred": workflow.bytes_transferred,
+        });
+
+        if let Some(details) = details {
+            body["details"] = json!(details);
+        }
+
+        info!("deployment event: {body}");
+
+        let message = IotMessage::builder()
+            .set_body(serde_json::to_vec(&body)?)
+            .set_content_type("application/json")
+            .set_content_encoding("utf-8")
+            .set_property("type", "deployment_event")
+            .build()?;
+
+        self.tx_d2c_messages
+            .send(message)
+            .await
+            .context("send_deployment_event: send")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn deployment_event_tracks_timings() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let telemetry = Telemetry::new(tx);
+        let mut workflow: Workflow = serde_json::from_value(json!({
+            "id": "wf",
+            "action": 3,
+            "update_id": {"provider": "p", "name": "n", "version": "1"},
+            "installed_criteria": null,
+            "phase": "Started"
+        }))
+        .unwrap();
+
+        telemetry
+            .deployment_event(&mut workflow, DeploymentEvent::Started, None)
+            .await;
+
+        let started_at = workflow.started_at.unwrap();
+        assert_eq!(workflow.phase_changed_at, Some(started_at));
+
+        telemetry
+            .deployment_event(&mut workflow, DeploymentEvent::Failed, Some("error"))
+            .await;
+
+        assert_eq!(workflow.started_at, Some(started_at));
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_some());
+    }
+}
@@ -0,0 +1,305 @@
+use super::{health, service_config};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use tokio::sync::mpsc::Sender;
+
+pub const WORKFLOW_FILE: &str = "workflow.json";
+pub const FAILED_WORKFLOW_FILE: &str = "failed-workflow.json";
+
+// agent states as defined by the device update pnp interface
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AgentState {
+    Idle = 0,
+    DeploymentInProgress = 6,
+    Failed = 255,
+}
+
+// result codes as reported in deviceUpdate.agent.lastInstallResult
+pub const RESULT_FAILURE: u32 = 0;
+pub const RESULT_APPLY_SUCCESS: u32 = 700;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StepResult {
+    pub result_code: u32,
+    pub extended_result_codes: String,
+    pub result_details: String,
+}
+
+impl StepResult {
+    pub fn success(details: impl Into<String>) -> Self {
+        StepResult {
+            result_code: RESULT_APPLY_SUCCESS,
+            extended_result_codes: "00000000".to_owned(),
+            result_details: details.into(),
+        }
+    }
+
+    pub fn failure(details: impl Into<String>) -> Self {
+        StepResult {
+            result_code: RESULT_FAILURE,
+            extended_result_codes: "00000000".to_owned(),
+            result_details: details.into(),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.result_code != RESULT_FAILURE
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallResult {
+    pub result_code: u32,
+    pub extended_result_codes: String,
+    pub result_details: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub step_results: BTreeMap<String, StepResult>,
+}
+
+impl InstallResult {
+    pub fn success() -> Self {
+        InstallResult {
+            result_code: RESULT_APPLY_SUCCESS,
+            extended_result_codes: "00000000".to_owned(),
+            result_details: String::new(),
+            step_results: BTreeMap::new(),
+        }
+    }
+
+    pub fn failure(details: impl Into<String>) -> Self {
+        InstallResult {
+            result_code: RESULT_FAILURE,
+            extended_result_codes: "00000000".to_owned(),
+            result_details: details.into(),
+            step_results: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_step(mut self, name: &str, step: StepResult) -> Self {
+        self.step_results.insert(name.to_owned(), step);
+        self
+    }
+
+    fn agent_state(&self) -> AgentState {
+        if self.result_code == RESULT_FAILURE {
+            AgentState::Failed
+        } else {
+            AgentState::Idle
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Phase {
+    Started,
+    Downloaded,
+    WaitingForMaintenanceWindow,
+    Installing,
+    Installed,
+    Applied,
+    WaitingForRebootWindow,
+    RebootPending,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Reboot {
+    // reboot once the deployment finished
+    Required,
+    // reboot right away
+    Immediate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApplyResult {
+    Success,
+    Reboot(Reboot),
+}
+
+// downloaded images, so that waiting for the install window can be resumed after restart
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PendingInstall {
+    pub images: Vec<Image>,
+    pub reboot: Option<Reboot>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Image {
+    pub path: PathBuf,
+    pub swupdate_arguments: Vec<String>,
+}
+
+fn state_file_path(file: &str) -> PathBuf {
+    service_config::paths().state_dir.join(file)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Workflow {
+    pub id: String,
+    pub action: u8,
+    pub update_id: serde_json::Value,
+    pub installed_criteria: Option<String>,
+    pub phase: Phase,
+    // unix timestamps and download size, used for telemetry
+    pub started_at: Option<i64>,
+    pub phase_changed_at: Option<i64>,
+    #[serde(default)]
+    pub bytes_transferred: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_install: Option<PendingInstall>,
+}
+
+impl Workflow {
+    pub fn new(
+        id: String,
+        action: u8,
+        update_id: serde_json::Value,
+        installed_criteria: Option<String>,
+    ) -> Self {
+        Workflow {
+            id,
+            action,
+            update_id,
+            installed_criteria,
+            phase: Phase::Started,
+            started_at: None,
+            phase_changed_at: None,
+            bytes_transferred: 0,
+            pending_install: None,
+        }
+    }
+
+    pub fn load() -> Result<Option<Self>> {
+        Self::load_file(WORKFLOW_FILE)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        info!("persist workflow {} in phase {:?}", self.id, self.phase);
+
+        self.save_file(WORKFLOW_FILE)
+    }
+
+    pub fn remove() -> Result<()> {
+        Self::remove_file(WORKFLOW_FILE)
+    }
+
+    // a deployment ended, either way, and is counted exactly once
+    pub fn finish() -> Result<()> {
+        Self::remove()?;
+        health::count_deployment();
+
+        Ok(())
+    }
+
+    // the last failed workflow is kept in order to be able to retry it
+    pub fn load_failed() -> Result<Option<Self>> {
+        Self::load_file(FAILED_WORKFLOW_FILE)
+    }
+
+    pub fn save_failed(&self) -> Result<()> {
+        info!("persist failed workflow {}", self.id);
+
+        self.save_file(FAILED_WORKFLOW_FILE)
+    }
+
+    pub fn remove_failed() -> Result<()> {
+        Self::remove_file(FAILED_WORKFLOW_FILE)
+    }
+
+    fn load_file(file: &str) -> Result<Option<Self>> {
+        let path = state_file_path(file);
+
+        if !path.exists() {
+            debug!("no persisted {file}");
+            return Ok(None);
+        }
+
+        let workflow = serde_json::from_str(
+            &fs::read_to_string(&path).context("load workflow: cannot read state file")?,
+        )
+        .context("load workflow: cannot parse state file")?;
+
+        Ok(Some(workflow))
+    }
+
+    fn save_file(&self, file: &str) -> Result<()> {
+        let path = state_file_path(file);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("save workflow: cannot create state dir")?;
+        }
+
+        let tmp_path = path.with_extension("tmp");
+
+        // write to a temporary file first, so that we never leave a partially written state
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)
+            .context("save workflow: cannot write state file")?;
+        fs::rename(&tmp_path, &path).context("save workflow: cannot rename state file")
+    }
+
+    fn remove_file(file: &str) -> Result<()> {
+        let path = state_file_path(file);
+
+        if path.exists() {
+            fs::remove_file(path).context("remove workflow: cannot remove state file")?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn report_state(
+        &self,
+        tx_reported_properties: &Sender<serde_json::Value>,
+        state: AgentState,
+    ) -> Result<()> {
+        tx_reported_properties
+            .send(json!({
+                "deviceUpdate": {
+                    "__t": "c",
+                    "agent": {
+                        "state": state as u8,
+                        "workflow": {
+                            "action": self.action,
+                            "id": self.id
+                        }
+                    }
+                }
+            }))
+            .await
+            .context("report_state: report_impl")
+    }
+
+    pub async fn report_result(
+        &self,
+        tx_reported_properties: &Sender<serde_json::Value>,
+        result: &InstallResult,
+    ) -> Result<()> {
+        let mut agent = json!({
+            "state": result.agent_state() as u8,
+            "workflow": {
+                "action": self.action,
+                "id": self.id
+            },
+            "lastInstallResult": serde_json::to_value(result)?
+        });
+
+        // only a successful deployment changes the installed update
+        if result.result_code != RESULT_FAILURE {
+            agent["installedUpdateId"] = json!(self.update_id.to_string());
+        }
+
+        tx_reported_properties
+            .send(json!({
+                "deviceUpdate": {
+                    "__t": "c",
+                    "agent": agent
+                }
+            }))
+            .await
+            .context("report_result: report_impl")
+    }
+}